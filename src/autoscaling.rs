@@ -1,8 +1,18 @@
-use crate::awsutils::{config, datetime_str, GlobalOpt};
-use crate::utils::{get_values, print_table, split, Tag};
-use aws_sdk_autoscaling::model::Instance;
+use crate::awsutils::{
+    clients, datetime_rfc3339, merge_scoped, scope_client, GlobalOpt, Scope, ScopeColumns, Scoped,
+};
+use crate::cache::{cached, Cached};
+use crate::error::{found, Result};
+use crate::filter::{apply, Filter};
+use crate::output::{print, OutputOpt};
+use crate::picker::choose;
+use crate::query::{Matcher, Searchable};
+use crate::utils::{get_values, split, Record, Tag};
+use crate::watch::{watch, WatchOpt};
 use aws_sdk_autoscaling::Client;
+use futures::future::join_all;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -10,9 +20,9 @@ pub enum AutoScalingGroupOpt {
     #[structopt(about = "display basic info")]
     Info(SearchInfoQueryOpt),
     #[structopt(visible_alias = "act", about = "display activities")]
-    Activities(SearchQueryOpt),
+    Activities(ActivitiesOpt),
     #[structopt(visible_alias = "inst", about = "display instances")]
-    Instances(SearchQueryOpt),
+    Instances(InstancesOpt),
 }
 #[derive(Debug, StructOpt)]
 pub struct SearchQueryOpt {
@@ -22,16 +32,54 @@ pub struct SearchQueryOpt {
         help = "ambiguous search with asterisk on target group name or ALB arn.  if set comma, search OR"
     )]
     query: Option<String>,
+    #[structopt(
+        short = "e",
+        long,
+        conflicts_with = "regex",
+        help = "exact search instead of ambiguous search. asterisk can be used as wildcard"
+    )]
+    exact: bool,
+    #[structopt(
+        short = "r",
+        long,
+        help = "regex search on auto scaling group name or tags. use | instead of comma for OR"
+    )]
+    regex: bool,
+    #[structopt(
+        short = "f",
+        long = "filter",
+        number_of_values = 1,
+        help = "filter with field expressions. if set multiple, search AND.
+    field=value (asterisk as wildcard), field~regex, field (exists), !expr (not)
+    e.g. -f desired=0 -f tag:env=prod -f 'name~^api' -f '!tag:ignore-monitor'"
+    )]
+    filters: Vec<Filter>,
 }
 
 #[derive(Debug, StructOpt)]
-pub struct SearchInfoQueryOpt {
+pub struct ActivitiesOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
     #[structopt(
-        short = "q",
         long,
-        help = "ambiguous search with asterisk on target group name or ALB arn.  if set comma, search OR"
+        help = "show activities of every matched auto scaling group, grouped by name. \
+    otherwise, choose one interactively when several match"
     )]
-    query: Option<String>,
+    all: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InstancesOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(flatten)]
+    watch: WatchOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SearchInfoQueryOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
     #[structopt(
         short = "T",
         long,
@@ -41,19 +89,35 @@ pub struct SearchInfoQueryOpt {
     tag_columns: Option<String>,
     #[structopt(long = "show-all-tags", help = "Show all tags.")]
     show_all_tags: bool,
+    #[structopt(flatten)]
+    watch: WatchOpt,
 }
 
-pub async fn matcher(global_opt: GlobalOpt, opt: AutoScalingGroupOpt) {
-    let cli = Client::new(&config(global_opt).await);
+pub async fn matcher(mut global_opt: GlobalOpt, opt: AutoScalingGroupOpt) -> Result<()> {
+    // every redraw fetches again
+    global_opt.cache.refresh |= match &opt {
+        AutoScalingGroupOpt::Info(o) => o.watch.is_set(),
+        AutoScalingGroupOpt::Instances(o) => o.watch.is_set(),
+        AutoScalingGroupOpt::Activities(_) => false,
+    };
+    let clis = clients::<Client>(&global_opt).await?;
+    let output = &global_opt.output;
     match opt {
-        AutoScalingGroupOpt::Info(opt) => info(&cli, opt).await,
-        AutoScalingGroupOpt::Activities(opt) => activities(&cli, opt).await,
-        AutoScalingGroupOpt::Instances(opt) => instances(&cli, opt).await,
+        AutoScalingGroupOpt::Info(mut opt) => {
+            opt.tag_columns = opt.tag_columns.or(global_opt.tag_columns);
+            let output = &opt.watch.output(output);
+            watch(&opt.watch, || info(&clis, output, &opt)).await
+        }
+        AutoScalingGroupOpt::Activities(opt) => activities(&clis, output, opt).await,
+        AutoScalingGroupOpt::Instances(opt) => {
+            let output = &opt.watch.output(output);
+            watch(&opt.watch, || instances(&clis, output, &opt.query)).await
+        }
     }
 }
-async fn info(cli: &Client, opt: SearchInfoQueryOpt) {
-    let asg = get_autoscaling_groups(cli, &SearchQueryOpt { query: opt.query }).await;
-    let len = asg.len();
+async fn info(clis: &[Scoped<Client>], output: &OutputOpt, opt: &SearchInfoQueryOpt) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let asg = get_autoscaling_groups(clis, &opt.query).await?;
 
     let tag_column: Vec<String> = if opt.show_all_tags {
         asg.iter()
@@ -62,101 +126,161 @@ async fn info(cli: &Client, opt: SearchInfoQueryOpt) {
             .collect()
     } else {
         opt.tag_columns
-            .map(|t| split(&*t, true))
+            .as_ref()
+            .map(|t| split(t, true))
             .unwrap_or_default()
     };
 
     let rows: Vec<Vec<String>> = asg
-        .into_iter()
+        .iter()
         .map(|t| {
             let r = get_values(&t.tags, &tag_column);
-            vec![
-                t.name,
+            let row = vec![
+                t.name.clone(),
                 t.instances.len().to_string(),
                 t.desired_capacity
                     .map(|i| i.to_string())
                     .unwrap_or_default(),
                 t.min_capacity.map(|i| i.to_string()).unwrap_or_default(),
                 t.max_capacity.map(|i| i.to_string()).unwrap_or_default(),
-            ]
-            .into_iter()
-            .chain(r)
-            .collect()
+            ];
+            columns.row(&t.scope, row.into_iter().chain(r).collect())
         })
         .collect();
-    let header: Vec<String> = vec![
-        "Name".to_string(),
-        "Instances".to_string(),
-        "Desired".to_string(),
-        "Min".to_string(),
-        "Max".to_string(),
-    ]
-    .into_iter()
-    .chain(tag_column)
-    .collect();
-    print_table(header, rows);
-    println!("counts: {}", len);
+    let header: Vec<String> = columns.header(
+        vec![
+            "Name".to_string(),
+            "Instances".to_string(),
+            "Desired".to_string(),
+            "Min".to_string(),
+            "Max".to_string(),
+        ]
+        .into_iter()
+        .chain(tag_column)
+        .collect(),
+    );
+    print(output, &asg, header, rows)?;
+    found(&asg, "auto scaling groups")
 }
-async fn activities(cli: &Client, opt: SearchQueryOpt) {
-    let asg = get_autoscaling_groups(cli, &opt).await;
-    if asg.len() != 1 {
-        println!("need to be narrowed to 1");
-        return;
-    }
-    let a = get_activities(cli, asg.first().unwrap().name.clone()).await;
-    let len = a.len();
+async fn activities(clis: &[Scoped<Client>], output: &OutputOpt, opt: ActivitiesOpt) -> Result<()> {
+    let asg = get_autoscaling_groups(clis, &opt.query).await?;
+    let groups: Vec<&AutoScalingGroup> = if opt.all {
+        found(&asg, "auto scaling groups")?;
+        asg.iter().sorted_by(|a, b| a.name.cmp(&b.name)).collect()
+    } else {
+        vec![choose(&asg, "auto scaling groups", label)?]
+    };
+    let a = join_all(groups.iter().map(|group| async move {
+        let cli = scope_client(clis, &group.scope)?;
+        get_activities(&cli.client, group).await
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    let columns = ScopeColumns::new(groups.iter().map(|g| &g.scope));
+    let group_column = |name: &str| {
+        if opt.all {
+            vec![name.to_string()]
+        } else {
+            vec![]
+        }
+    };
     let rows: Vec<Vec<String>> = a
-        .into_iter()
-        .map(|t| vec![t.status, t.description, t.start_at, t.end_at])
+        .iter()
+        .map(|t| {
+            let row = vec![
+                t.status.clone(),
+                t.description.clone(),
+                t.start_at.clone(),
+                t.end_at.clone(),
+            ];
+            columns.row(
+                &t.scope,
+                group_column(&t.auto_scaling_group)
+                    .into_iter()
+                    .chain(row)
+                    .collect(),
+            )
+        })
         .collect();
-    print_table(
-        vec![
+    let header = group_column("ASG Name")
+        .into_iter()
+        .chain(vec![
             "Status".to_string(),
             "Desc".to_string(),
             "StartTime".to_string(),
             "EndTime".to_string(),
-        ],
-        rows,
-    );
-    println!("counts: {}", len);
+        ])
+        .collect();
+    print(output, &a, columns.header(header), rows)?;
+    Ok(())
 }
 
-async fn instances(cli: &Client, opt: SearchQueryOpt) {
-    let asg = get_autoscaling_groups(cli, &opt).await;
-    let rows: Vec<Vec<String>> = asg
-        .into_iter()
+// a line for the interactive picker
+fn label(g: &AutoScalingGroup) -> String {
+    format!(
+        "{}  instances:{}  {}",
+        g.name,
+        g.instances.len(),
+        g.scope.region
+    )
+}
+
+async fn instances(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    opt: &SearchQueryOpt,
+) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let asg = get_autoscaling_groups(clis, opt).await?;
+    let instances: Vec<GroupInstance> = asg
+        .iter()
         .flat_map(|a| {
-            let name = a.name;
-            a.instances
-                .into_iter()
-                .map(|i| {
-                    vec![
-                        name.clone(),
-                        i.instance_id.unwrap_or_default(),
-                        i.lifecycle_state
-                            .map(|s| s.as_str().to_string())
-                            .unwrap_or_default(),
-                        i.instance_type.unwrap_or_default(),
-                        i.availability_zone.unwrap_or_default(),
-                        i.health_status.unwrap_or_default(),
-                    ]
-                })
-                .collect::<Vec<_>>()
+            a.instances.iter().map(|i| GroupInstance {
+                auto_scaling_group: &a.name,
+                instance: i,
+                tags: &a.tags,
+                scope: &a.scope,
+            })
         })
         .collect();
-    print_table(
-        vec![
+    let rows: Vec<Vec<String>> = instances
+        .iter()
+        .map(|i| {
+            columns.row(
+                i.scope,
+                vec![
+                    i.auto_scaling_group.to_string(),
+                    i.instance.id.clone(),
+                    i.instance.lifecycle.clone(),
+                    i.instance.instance_type.clone(),
+                    i.instance.az.clone(),
+                    i.instance.health_status.clone(),
+                ],
+            )
+        })
+        .collect();
+    print(
+        output,
+        &instances,
+        columns.header(vec![
             "ASG Name".to_string(),
             "ID".to_string(),
             "LifeCycle".to_string(),
             "InstanceType".to_string(),
             "AZ".to_string(),
             "Status".to_string(),
-        ],
+        ]),
         rows,
-    )
+    )?;
+    found(&asg, "auto scaling groups")
 }
 
+#[derive(Serialize, Deserialize)]
 struct AutoScalingGroup {
     name: String,
     instances: Vec<Instance>,
@@ -164,155 +288,261 @@ struct AutoScalingGroup {
     max_capacity: Option<i32>,
     desired_capacity: Option<i32>,
     tags: Vec<Tag>,
+    #[serde(flatten)]
+    scope: Scope,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Instance {
+    id: String,
+    lifecycle: String,
+    instance_type: String,
+    az: String,
+    health_status: String,
+}
+
+// an instance of a group, a row of `asg instances`
+#[derive(Serialize)]
+struct GroupInstance<'a> {
+    auto_scaling_group: &'a str,
+    #[serde(flatten)]
+    instance: &'a Instance,
+    // tags of the group
+    #[serde(skip)]
+    tags: &'a [Tag],
+    #[serde(flatten)]
+    scope: &'a Scope,
+}
+
+impl Record for GroupInstance<'_> {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "auto_scaling_group" => self.auto_scaling_group.to_string(),
+            "id" => self.instance.id.clone(),
+            "lifecycle" => self.instance.lifecycle.clone(),
+            "instance_type" => self.instance.instance_type.clone(),
+            "az" => self.instance.az.clone(),
+            "health_status" => self.instance.health_status.clone(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
+    }
+    fn tags(&self) -> &[Tag] {
+        self.tags
+    }
+}
+
+async fn get_autoscaling_groups(
+    clis: &[Scoped<Client>],
+    opt: &SearchQueryOpt,
+) -> Result<Vec<AutoScalingGroup>> {
+    let matcher = Matcher::new(opt.query.as_deref(), opt.exact, opt.regex)?;
+    let vector = join_all(clis.iter().map(scoped_autoscaling_groups)).await;
+    let asg = merge_scoped(clis, vector)?
+        .into_iter()
+        .filter(|t| matcher.matches(t))
+        .collect();
+    Ok(apply(&opt.filters, asg)?)
 }
 
-async fn get_autoscaling_groups(cli: &Client, opt: &SearchQueryOpt) -> Vec<AutoScalingGroup> {
+async fn scoped_autoscaling_groups(cli: &Scoped<Client>) -> Result<Vec<AutoScalingGroup>> {
+    let fetch = fetch_autoscaling_groups(cli);
+    cached(&cli.cache, &cli.scope, "auto_scaling_groups", true, fetch).await
+}
+async fn fetch_autoscaling_groups(cli: &Scoped<Client>) -> Result<Vec<AutoScalingGroup>> {
     let mut m: Option<String> = None;
     let mut vector: Vec<AutoScalingGroup> = vec![];
     loop {
-        let (mut v, mark) = autoscaling_groups(cli, &m).await;
+        let (mut v, mark) = autoscaling_groups(cli, &m).await?;
         m = mark;
         vector.append(&mut v);
         if m.is_none() {
             break;
         }
     }
-    vector
-        .into_iter()
-        .filter(|t| search_name(&opt.query, &t.name, &t.tags))
-        .collect()
+    Ok(vector)
 }
 
 async fn autoscaling_groups(
-    client: &Client,
+    cli: &Scoped<Client>,
     marker: &Option<String>,
-) -> (Vec<AutoScalingGroup>, Option<String>) {
-    let resp = client
+) -> Result<(Vec<AutoScalingGroup>, Option<String>)> {
+    let res = cli
+        .client
         .describe_auto_scaling_groups()
         .set_next_token(marker.clone())
-        .send();
-    match resp.await {
-        Ok(res) => {
-            let groups = res.auto_scaling_groups.unwrap_or_default();
-            (
-                groups
+        .send()
+        .await?;
+    let groups = res.auto_scaling_groups.unwrap_or_default();
+    Ok((
+        groups
+            .into_iter()
+            .map(|t| AutoScalingGroup {
+                name: t.auto_scaling_group_name.unwrap_or_default(),
+                instances: t
+                    .instances
+                    .unwrap_or_default()
                     .into_iter()
-                    .map(|t| AutoScalingGroup {
-                        name: t.auto_scaling_group_name.unwrap_or_default(),
-                        instances: t.instances.unwrap_or_default(),
-                        min_capacity: t.min_size,
-                        max_capacity: t.max_size,
-                        desired_capacity: t.desired_capacity,
-                        tags: t
-                            .tags
-                            .map(|ot| {
-                                ot.into_iter()
-                                    .map(|t| Tag {
-                                        key: t.key.unwrap_or_default(),
-                                        value: t.value,
-                                    })
-                                    .collect()
-                            })
+                    .map(|i| Instance {
+                        id: i.instance_id.unwrap_or_default(),
+                        lifecycle: i
+                            .lifecycle_state
+                            .map(|s| s.as_str().to_string())
                             .unwrap_or_default(),
+                        instance_type: i.instance_type.unwrap_or_default(),
+                        az: i.availability_zone.unwrap_or_default(),
+                        health_status: i.health_status.unwrap_or_default(),
                     })
                     .collect(),
-                res.next_token,
-            )
-        }
-        Err(err) => panic!("{}", err.to_string()),
+                min_capacity: t.min_size,
+                max_capacity: t.max_size,
+                desired_capacity: t.desired_capacity,
+                tags: t
+                    .tags
+                    .map(|ot| {
+                        ot.into_iter()
+                            .map(|t| Tag {
+                                key: t.key.unwrap_or_default(),
+                                value: t.value,
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                scope: cli.scope.clone(),
+            })
+            .collect(),
+        res.next_token,
+    ))
+}
+
+impl Cached for AutoScalingGroup {
+    fn set_scope(&mut self, scope: &Scope) {
+        self.scope = scope.clone();
     }
 }
 
-fn search_name(query: &Option<String>, name: &str, tags: &[Tag]) -> bool {
-    if query.is_none() || name.is_empty() {
-        return true;
+impl Record for AutoScalingGroup {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "name" => self.name.clone(),
+            "instances" => self.instances.len().to_string(),
+            "desired" => self
+                .desired_capacity
+                .map(|i| i.to_string())
+                .unwrap_or_default(),
+            "min" => self.min_capacity.map(|i| i.to_string()).unwrap_or_default(),
+            "max" => self.max_capacity.map(|i| i.to_string()).unwrap_or_default(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
     }
-    let tg: String = name.to_string();
-    let qu: String = query.as_ref().unwrap().clone();
-    for q in qu.split(',') {
-        if tg.contains(q) {
-            return true;
-        }
-        if tags
-            .iter()
-            .any(|t| t.key.contains(q) || t.value.as_ref().filter(|v| v.contains(q)).is_some())
-        {
-            return true;
-        }
+    fn tags(&self) -> &[Tag] {
+        &self.tags
     }
+}
 
-    false
+impl Searchable for AutoScalingGroup {
+    fn search_keys(&self) -> Vec<&str> {
+        std::iter::once(self.name.as_str())
+            .chain(
+                self.tags
+                    .iter()
+                    .flat_map(|t| std::iter::once(t.key.as_str()).chain(t.value.as_deref())),
+            )
+            .collect()
+    }
 }
 #[test]
-fn test_search_name() {
-    assert_eq!(
-        search_name(&Some("api".to_string()), &"aa".to_string(), &vec![]),
-        false
-    );
-
-    assert_eq!(
-        search_name(
-            &Some("api,test".to_string()),
-            &"test-api".to_string(),
-            &vec![]
-        ),
-        true
-    );
-    assert_eq!(
-        search_name(&Some("api".to_string()), &"ap".to_string(), &vec![]),
-        false
-    );
-    assert_eq!(
-        search_name(
-            &Some("test".to_string()),
-            &"ap".to_string(),
-            &vec![Tag {
+fn test_search() {
+    let asg = |name: &str, tags: Vec<Tag>| AutoScalingGroup {
+        name: name.to_string(),
+        instances: vec![],
+        min_capacity: None,
+        max_capacity: None,
+        desired_capacity: None,
+        tags,
+        scope: Scope::default(),
+    };
+    let search = |q: &str, exact: bool, a: &AutoScalingGroup| {
+        Matcher::new(Some(q), exact, false).unwrap().matches(a)
+    };
+    assert!(!search("api", false, &asg("aa", vec![])));
+    assert!(search("api,test", false, &asg("test-api", vec![])));
+    assert!(!search("api", false, &asg("ap", vec![])));
+    assert!(search(
+        "test",
+        false,
+        &asg(
+            "ap",
+            vec![Tag {
                 key: "test".to_string(),
                 value: None
             }]
-        ),
-        true
-    );
-    assert_eq!(
-        search_name(
-            &Some("test".to_string()),
-            &"ap".to_string(),
-            &vec![Tag {
+        )
+    ));
+    assert!(search(
+        "test",
+        false,
+        &asg(
+            "ap",
+            vec![Tag {
                 key: "tag".to_string(),
                 value: Some("test".to_string())
             }]
-        ),
-        true
-    );
+        )
+    ));
+    assert!(search("api", true, &asg("api", vec![])));
+    assert!(!search("api", true, &asg("api-worker-canary", vec![])));
 }
 
+#[derive(Serialize)]
 struct Activity {
+    auto_scaling_group: String,
     status: String,
     description: String,
     start_at: String,
     end_at: String,
+    #[serde(flatten)]
+    scope: Scope,
 }
-async fn get_activities(cli: &Client, asg_name: String) -> Vec<Activity> {
-    match cli
+
+impl Record for Activity {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "auto_scaling_group" => self.auto_scaling_group.clone(),
+            "status" => self.status.clone(),
+            "description" => self.description.clone(),
+            "start_at" => self.start_at.clone(),
+            "end_at" => self.end_at.clone(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
+    }
+    fn tags(&self) -> &[Tag] {
+        &[]
+    }
+}
+
+async fn get_activities(cli: &Client, group: &AutoScalingGroup) -> Result<Vec<Activity>> {
+    let res = cli
         .describe_scaling_activities()
-        .auto_scaling_group_name(asg_name)
+        .auto_scaling_group_name(group.name.clone())
         .send()
-        .await
-    {
-        Ok(res) => res
-            .activities
-            .unwrap_or_default()
-            .into_iter()
-            .map(|a| Activity {
-                status: a
-                    .status_code
-                    .map(|c| c.as_str().to_string())
-                    .unwrap_or_default(),
-                description: a.description.unwrap_or_default(),
-                start_at: a.start_time.map(datetime_str).unwrap_or_default(),
-                end_at: a.end_time.map(datetime_str).unwrap_or_default(),
-            })
-            .collect(),
-        Err(err) => panic!("{}", err.to_string()),
-    }
+        .await?;
+    Ok(res
+        .activities
+        .unwrap_or_default()
+        .into_iter()
+        .map(|a| Activity {
+            auto_scaling_group: group.name.clone(),
+            status: a
+                .status_code
+                .map(|c| c.as_str().to_string())
+                .unwrap_or_default(),
+            description: a.description.unwrap_or_default(),
+            start_at: a.start_time.map(datetime_rfc3339).unwrap_or_default(),
+            end_at: a.end_time.map(datetime_rfc3339).unwrap_or_default(),
+            scope: group.scope.clone(),
+        })
+        .collect())
 }
@@ -1,23 +1,476 @@
+use crate::cache::{cache_identity, cached_regions, CacheOpt};
+use crate::error::{Error, Result};
+use crate::output::OutputOpt;
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
+use aws_config::default_provider::region::DefaultRegionChain;
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::sts::AssumeRoleProvider;
+use aws_smithy_http::endpoint::Endpoint;
 use aws_smithy_types::date_time::Format;
 use aws_smithy_types::DateTime;
+use aws_types::credentials::{ProvideCredentials, SharedCredentialsProvider};
+use aws_types::os_shim_internal::{Env, Fs};
 use aws_types::region::Region;
 use aws_types::sdk_config::SdkConfig;
+use futures::future::join_all;
+use http::Uri;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 
-pub fn datetime_str(dt: DateTime) -> String {
-    match dt.fmt(Format::HttpDate) {
+// RFC-3339 keeps the lexical order of the time, so it is suitable for fields
+pub fn datetime_rfc3339(dt: DateTime) -> String {
+    format_datetime(dt, Format::DateTime)
+}
+
+fn format_datetime(dt: DateTime, format: Format) -> String {
+    match dt.fmt(format) {
         Ok(r) => r,
         Err(err) => panic!("{}", err.to_string()),
     }
 }
+#[test]
+fn test_datetime_rfc3339() {
+    assert_eq!(
+        datetime_rfc3339(DateTime::from_secs(1_600_000_000)),
+        "2020-09-13T12:26:40Z"
+    );
+}
 
 pub struct GlobalOpt {
     pub region: Option<String>,
+    pub regions: Vec<String>,
+    pub all_regions: bool,
+    pub profiles: Vec<String>,
+    pub assume_roles: Vec<String>,
+    pub endpoint_url: Option<Uri>,
+    pub output: OutputOpt,
+    pub cache: CacheOpt,
+    // the default of -T for the resource of the command
+    pub tag_columns: Option<String>,
 }
 
-pub async fn config(opt: GlobalOpt) -> SdkConfig {
-    let region_provider = RegionProviderChain::first_try(opt.region.map(Region::new))
+// a named profile and a role to assume. credentials are loaded from the default chain if both are none
+pub async fn config(
+    region: Option<String>,
+    profile: Option<&str>,
+    role: Option<&str>,
+) -> SdkConfig {
+    let mut region_provider = RegionProviderChain::first_try(region.map(Region::new));
+    if let Some(p) = profile {
+        region_provider =
+            region_provider.or_else(DefaultRegionChain::builder().profile_name(p).build());
+    }
+    let region = region_provider
         .or_default_provider()
-        .or_else(Region::new("us-west-2"));
-    aws_config::from_env().region(region_provider).load().await
+        .region()
+        .await
+        .unwrap_or_else(|| Region::new("us-west-2"));
+    let loader = aws_config::from_env().region(region.clone());
+    if profile.is_none() && role.is_none() {
+        return loader.load().await;
+    }
+    let mut chain = DefaultCredentialsChain::builder().region(region.clone());
+    if let Some(p) = profile {
+        chain = chain.profile_name(p);
+    }
+    let chain = chain.build().await;
+    let loader = match role {
+        Some(arn) => loader.credentials_provider(
+            AssumeRoleProvider::builder(arn)
+                .session_name("ec2s")
+                .region(region)
+                .build(SharedCredentialsProvider::new(chain)),
+        ),
+        None => loader.credentials_provider(chain),
+    };
+    loader.load().await
+}
+
+// credentials of the assumed role as environment variables, for child processes such as the aws cli
+pub async fn role_env(
+    region: &str,
+    profile: Option<&str>,
+    role: &str,
+) -> Result<Vec<(&'static str, String)>> {
+    let c = config(Some(region.to_string()), profile, Some(role)).await;
+    let credentials = match c.credentials_provider() {
+        Some(p) => p
+            .provide_credentials()
+            .await
+            .map_err(|e| Error::Credentials(e.to_string()))?,
+        None => return Err(Error::Credentials(format!("unable to assume {}", role))),
+    };
+    let mut env = vec![
+        ("AWS_ACCESS_KEY_ID", credentials.access_key_id().to_string()),
+        (
+            "AWS_SECRET_ACCESS_KEY",
+            credentials.secret_access_key().to_string(),
+        ),
+    ];
+    if let Some(token) = credentials.session_token() {
+        env.push(("AWS_SESSION_TOKEN", token.to_string()));
+    }
+    Ok(env)
+}
+
+// where results come from
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scope {
+    pub region: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub profile: String,
+    // account id of the assumed role
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub account: String,
+}
+
+impl Scope {
+    // profile/account/region, for messages
+    pub fn label(&self) -> String {
+        [&self.profile, &self.account, &self.region]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .join("/")
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<String> {
+        match key {
+            "region" => Some(self.region.clone()),
+            "profile" => Some(self.profile.clone()),
+            "account" => Some(self.account.clone()),
+            _ => None,
+        }
+    }
+}
+
+pub struct Scoped<C> {
+    pub scope: Scope,
+    pub client: C,
+    pub cache: CacheOpt,
+}
+
+// build a client for each profile and region to search
+pub async fn clients<C: FromConfig>(opt: &GlobalOpt) -> Result<Vec<Scoped<C>>> {
+    Ok(configs(opt)
+        .await?
+        .into_iter()
+        .map(|(scope, c, endpoint)| Scoped {
+            client: C::from_config(&c, endpoint.as_ref()),
+            cache: CacheOpt {
+                identity: cache_identity(&scope.profile, &scope.account, endpoint.as_ref()),
+                ..opt.cache.clone()
+            },
+            scope,
+        })
+        .collect())
+}
+
+// the client a record was fetched with
+pub fn scope_client<'a, C>(clis: &'a [Scoped<C>], scope: &Scope) -> Result<&'a Scoped<C>> {
+    clis.iter()
+        .find(|c| c.scope == *scope)
+        .ok_or_else(|| Error::Api(format!("no client for {}", scope.label())))
+}
+
+// merge the results of each client. when searching several scopes, a failed one
+// (e.g. a region denied by an SCP) is skipped with a warning so that the others are still shown
+pub fn merge_scoped<C, T>(clis: &[Scoped<C>], results: Vec<Result<Vec<T>>>) -> Result<Vec<T>> {
+    if clis.len() <= 1 {
+        return Ok(results
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect());
+    }
+    let mut merged = vec![];
+    let mut first_err = None;
+    let mut succeeded = false;
+    for (cli, result) in clis.iter().zip(results) {
+        match result {
+            Ok(r) => {
+                succeeded = true;
+                merged.extend(r);
+            }
+            Err(e) => {
+                eprintln!("Warning: skipped {}: {}", cli.scope.label(), e);
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    match first_err {
+        Some(e) if !succeeded => Err(e),
+        _ => Ok(merged),
+    }
+}
+#[test]
+fn test_merge_scoped() {
+    let cli = |region: &str| Scoped {
+        scope: Scope {
+            region: region.to_string(),
+            ..Default::default()
+        },
+        client: (),
+        cache: CacheOpt::default(),
+    };
+    let denied = || Err(Error::AccessDenied("m".to_string()));
+    let one = vec![cli("us-east-1")];
+    assert!(merge_scoped::<_, i32>(&one, vec![denied()]).is_err());
+    let two = vec![cli("us-east-1"), cli("eu-west-1")];
+    assert_eq!(merge_scoped(&two, vec![Ok(vec![1]), denied()]), Ok(vec![1]));
+    assert_eq!(
+        merge_scoped::<_, i32>(&two, vec![denied(), denied()]),
+        denied()
+    );
+}
+
+// a service client which can be sent to a custom endpoint
+pub trait FromConfig {
+    fn from_config(c: &SdkConfig, endpoint: Option<&Uri>) -> Self;
+}
+
+impl FromConfig for aws_sdk_ec2::Client {
+    fn from_config(c: &SdkConfig, endpoint: Option<&Uri>) -> Self {
+        let mut b = aws_sdk_ec2::config::Builder::from(c);
+        if let Some(uri) = endpoint {
+            b = b.endpoint_resolver(Endpoint::immutable(uri.clone()));
+        }
+        Self::from_conf(b.build())
+    }
+}
+
+impl FromConfig for aws_sdk_elasticloadbalancingv2::Client {
+    fn from_config(c: &SdkConfig, endpoint: Option<&Uri>) -> Self {
+        let mut b = aws_sdk_elasticloadbalancingv2::config::Builder::from(c);
+        if let Some(uri) = endpoint {
+            b = b.endpoint_resolver(Endpoint::immutable(uri.clone()));
+        }
+        Self::from_conf(b.build())
+    }
+}
+
+impl FromConfig for aws_sdk_autoscaling::Client {
+    fn from_config(c: &SdkConfig, endpoint: Option<&Uri>) -> Self {
+        let mut b = aws_sdk_autoscaling::config::Builder::from(c);
+        if let Some(uri) = endpoint {
+            b = b.endpoint_resolver(Endpoint::immutable(uri.clone()));
+        }
+        Self::from_conf(b.build())
+    }
+}
+
+async fn configs(opt: &GlobalOpt) -> Result<Vec<(Scope, SdkConfig, Option<Uri>)>> {
+    let profiles = if opt.profiles.is_empty() {
+        vec![None]
+    } else {
+        opt.profiles.iter().map(|p| Some(p.as_str())).collect()
+    };
+    let roles = if opt.assume_roles.is_empty() {
+        vec![None]
+    } else {
+        opt.assume_roles.iter().map(|r| Some(r.as_str())).collect()
+    };
+    // every role is assumed with the credentials of every profile
+    let pairs = profiles
+        .iter()
+        .flat_map(|p| roles.iter().map(move |r| (*p, *r)));
+    let configs = join_all(pairs.map(|(p, r)| profile_configs(opt, p, r)))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    Ok(configs.into_iter().flatten().collect())
+}
+
+async fn profile_configs(
+    opt: &GlobalOpt,
+    profile: Option<&str>,
+    role: Option<&str>,
+) -> Result<Vec<(Scope, SdkConfig, Option<Uri>)>> {
+    let endpoint = match &opt.endpoint_url {
+        Some(uri) => Some(uri.clone()),
+        None => profile_endpoint(profile).await,
+    };
+    let account = role.and_then(role_account).unwrap_or_default();
+    let regions = if opt.all_regions && opt.cache.offline {
+        cached_regions(&cache_identity(
+            profile.unwrap_or_default(),
+            account,
+            endpoint.as_ref(),
+        ))
+        .into_iter()
+        .map(Some)
+        .collect()
+    } else if opt.all_regions {
+        let base = config(opt.region.clone(), profile, role).await;
+        let cli = aws_sdk_ec2::Client::from_config(&base, endpoint.as_ref());
+        enabled_regions(&cli).await?.into_iter().map(Some).collect()
+    } else if !opt.regions.is_empty() {
+        opt.regions.iter().cloned().map(Some).collect()
+    } else {
+        vec![opt.region.clone()]
+    };
+    let endpoint = &endpoint;
+    Ok(join_all(regions.into_iter().map(|r| async move {
+        let c = config(r, profile, role).await;
+        let scope = Scope {
+            region: c.region().map(|r| r.to_string()).unwrap_or_default(),
+            profile: profile.unwrap_or_default().to_string(),
+            account: account.to_string(),
+        };
+        (scope, c, endpoint.clone())
+    }))
+    .await)
+}
+
+// `endpoint_url` of the profile in the shared config file, the same key as the aws cli
+async fn profile_endpoint(profile: Option<&str>) -> Option<Uri> {
+    let profiles = aws_config::profile::load(&Fs::real(), &Env::real())
+        .await
+        .ok()?;
+    let url = match profile {
+        Some(p) => profiles.get_profile(p)?.get("endpoint_url"),
+        None => profiles.get("endpoint_url"),
+    }?;
+    match url.parse() {
+        Ok(uri) => Some(uri),
+        Err(err) => {
+            eprintln!("Warning: ignore invalid endpoint_url '{}': {}", url, err);
+            None
+        }
+    }
+}
+
+// arn:aws:iam::123456789012:role/example => 123456789012
+fn role_account(arn: &str) -> Option<&str> {
+    arn.split(':').nth(4).filter(|a| !a.is_empty())
+}
+// the role assumed to search the scope
+pub fn scope_role<'a>(roles: &'a [String], scope: &Scope) -> Option<&'a str> {
+    match roles {
+        [role] => Some(role),
+        _ => roles
+            .iter()
+            .find(|r| role_account(r) == Some(scope.account.as_str()))
+            .map(String::as_str),
+    }
+}
+
+#[test]
+fn test_role_account() {
+    assert_eq!(
+        role_account("arn:aws:iam::123456789012:role/example"),
+        Some("123456789012")
+    );
+    assert_eq!(role_account("example"), None);
+
+    let roles = vec![
+        "arn:aws:iam::111111111111:role/readonly".to_string(),
+        "arn:aws:iam::222222222222:role/readonly".to_string(),
+    ];
+    let scope = Scope {
+        account: "222222222222".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(scope_role(&roles, &scope), Some(roles[1].as_str()));
+    assert_eq!(
+        scope_role(&roles[..1], &Scope::default()),
+        Some(roles[0].as_str())
+    );
+    assert_eq!(scope_role(&[], &scope), None);
+}
+
+async fn enabled_regions(cli: &aws_sdk_ec2::Client) -> Result<Vec<String>> {
+    let res = cli.describe_regions().send().await?;
+    Ok(res
+        .regions
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|r| r.region_name)
+        .sorted()
+        .collect())
+}
+
+// columns to tell scopes apart. shown only when results come from several scopes
+pub struct ScopeColumns {
+    profile: bool,
+    account: bool,
+    region: bool,
+}
+
+impl ScopeColumns {
+    pub fn new<'a>(scopes: impl Iterator<Item = &'a Scope>) -> ScopeColumns {
+        let scopes: Vec<&Scope> = scopes.collect();
+        ScopeColumns {
+            profile: scopes.iter().map(|s| &s.profile).unique().count() > 1,
+            account: scopes.iter().map(|s| &s.account).unique().count() > 1,
+            region: scopes.iter().map(|s| &s.region).unique().count() > 1,
+        }
+    }
+    // prepend scope columns to the header
+    pub fn header(&self, header: Vec<String>) -> Vec<String> {
+        let mut h = vec![];
+        if self.profile {
+            h.push("Profile".to_string());
+        }
+        if self.account {
+            h.push("Account".to_string());
+        }
+        if self.region {
+            h.push("Region".to_string());
+        }
+        h.into_iter().chain(header).collect()
+    }
+    // prepend scope columns to the row
+    pub fn row(&self, scope: &Scope, row: Vec<String>) -> Vec<String> {
+        let mut r = vec![];
+        if self.profile {
+            r.push(scope.profile.clone());
+        }
+        if self.account {
+            r.push(scope.account.clone());
+        }
+        if self.region {
+            r.push(scope.region.clone());
+        }
+        r.into_iter().chain(row).collect()
+    }
+}
+#[test]
+fn test_scope_columns() {
+    let tokyo = Scope {
+        region: "ap-northeast-1".to_string(),
+        ..Default::default()
+    };
+    let oregon = Scope {
+        region: "us-west-2".to_string(),
+        ..Default::default()
+    };
+    let single = ScopeColumns::new(vec![&tokyo].into_iter());
+    assert_eq!(
+        single.header(vec!["ID".to_string()]),
+        vec!["ID".to_string()]
+    );
+    assert_eq!(
+        single.row(&tokyo, vec!["i-1".to_string()]),
+        vec!["i-1".to_string()]
+    );
+    let multi = ScopeColumns::new(vec![&tokyo, &oregon].into_iter());
+    assert_eq!(
+        multi.header(vec!["ID".to_string()]),
+        vec!["Region".to_string(), "ID".to_string()]
+    );
+    assert_eq!(
+        multi.row(&oregon, vec!["i-1".to_string()]),
+        vec!["us-west-2".to_string(), "i-1".to_string()]
+    );
+
+    let staging = Scope {
+        profile: "staging".to_string(),
+        ..oregon.clone()
+    };
+    let accounts = ScopeColumns::new(vec![&oregon, &staging].into_iter());
+    assert_eq!(
+        accounts.row(&staging, vec!["i-1".to_string()]),
+        vec!["staging".to_string(), "i-1".to_string()]
+    );
 }
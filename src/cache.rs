@@ -0,0 +1,259 @@
+use crate::awsutils::Scope;
+use crate::error::{Error, Result};
+use http::Uri;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// how to use the results of previous runs
+#[derive(Clone, Debug, Default)]
+pub struct CacheOpt {
+    // results older than this are fetched again
+    pub ttl: Duration,
+    // don't read the cache, but still write it
+    pub refresh: bool,
+    // never call the api. results are read from the cache however old they are
+    pub offline: bool,
+    // the directory of the credentials and the endpoint. set for each client
+    pub identity: String,
+}
+
+// $XDG_CACHE_HOME/ec2s or ~/.cache/ec2s
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))?;
+    Some(base.join("ec2s"))
+}
+
+// one directory for each credentials and endpoint, so that accounts never share results.
+// the credentials are chosen in the same order as the default chain: keys in the environment,
+// then the profile
+pub fn cache_identity(profile: &str, account: &str, endpoint: Option<&Uri>) -> String {
+    let var = |k: &str| env::var(k).ok().filter(|v| !v.is_empty());
+    let credentials = match (var("AWS_ACCESS_KEY_ID"), profile) {
+        (Some(key), _) => format!("key-{}", key),
+        (None, "") => format!(
+            "profile-{}",
+            var("AWS_PROFILE").unwrap_or_else(|| "default".to_string())
+        ),
+        (None, p) => format!("profile-{}", p),
+    };
+    let endpoint = endpoint.and_then(|u| u.authority()).map(|a| a.as_str());
+    identity_dir(&credentials, account, endpoint)
+}
+
+// credentials, +account of the assumed role and @endpoint
+fn identity_dir(credentials: &str, account: &str, endpoint: Option<&str>) -> String {
+    let mut dir = credentials.to_string();
+    if !account.is_empty() {
+        dir = format!("{}+{}", dir, account);
+    }
+    if let Some(e) = endpoint {
+        dir = format!("{}@{}", dir, e);
+    }
+    dir.chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "-_.+@".contains(c) => c,
+            _ => '_',
+        })
+        .collect()
+}
+#[test]
+fn test_identity_dir() {
+    assert_eq!(identity_dir("profile-default", "", None), "profile-default");
+    assert_eq!(
+        identity_dir("profile-prod", "123456789012", Some("localhost:4566")),
+        "profile-prod+123456789012@localhost_4566"
+    );
+    assert_eq!(identity_dir("profile-a/../b", "", None), "profile-a_.._b");
+}
+
+fn cache_file(base: &Path, opt: &CacheOpt, scope: &Scope, resource: &str) -> PathBuf {
+    base.join(&opt.identity)
+        .join(&scope.region)
+        .join(format!("{}.json", resource))
+}
+#[test]
+fn test_cache_file() {
+    let opt = CacheOpt {
+        identity: "profile-default+123456789012".to_string(),
+        ..Default::default()
+    };
+    let scope = Scope {
+        region: "us-east-1".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        cache_file(Path::new("/c"), &opt, &scope, "instances"),
+        PathBuf::from("/c/profile-default+123456789012/us-east-1/instances.json")
+    );
+}
+
+// regions which have been cached for the identity. used instead of the api with --offline
+pub fn cached_regions(identity: &str) -> Vec<String> {
+    let dir = match cache_dir() {
+        Some(d) => d.join(identity),
+        None => return vec![],
+    };
+    let mut regions: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    regions.sort();
+    regions
+}
+
+// a cached record. the scope it was stored with may be stale, as `AWS_PROFILE=x` and
+// `--profile x` share a cache, so it's replaced with the scope of the client reading it
+pub trait Cached {
+    fn set_scope(&mut self, scope: &Scope);
+}
+
+// read the records of the scope from the cache, or fetch and cache them.
+// `complete` tells that fetch returns all records of the scope, so that they can be cached
+pub async fn cached<T, F>(
+    opt: &CacheOpt,
+    scope: &Scope,
+    resource: &str,
+    complete: bool,
+    fetch: F,
+) -> Result<Vec<T>>
+where
+    T: Cached + Serialize + DeserializeOwned,
+    F: Future<Output = Result<Vec<T>>>,
+{
+    let path = cache_dir().map(|d| cache_file(&d, opt, scope, resource));
+    let read = |p: &Path, ttl| {
+        read::<T>(p, ttl).map(|mut records| {
+            records.iter_mut().for_each(|r| r.set_scope(scope));
+            records
+        })
+    };
+    if opt.offline {
+        return match path.as_deref().and_then(|p| read(p, None)) {
+            Some(records) => Ok(records),
+            None => Err(Error::NotFound(format!(
+                "no cached {} in {}. run once without --offline",
+                resource, scope.region
+            ))),
+        };
+    }
+    if !opt.refresh {
+        if let Some(records) = path.as_deref().and_then(|p| read(p, Some(opt.ttl))) {
+            return Ok(records);
+        }
+    }
+    let records = fetch.await?;
+    if let Some(p) = path.filter(|_| complete) {
+        if let Err(e) = write(&p, &records) {
+            eprintln!("Warning: failed to write cache {}: {}", p.display(), e);
+        }
+    }
+    Ok(records)
+}
+
+// None when missing, broken or older than ttl
+fn read<T: DeserializeOwned>(path: &Path, ttl: Option<Duration>) -> Option<Vec<T>> {
+    if let Some(ttl) = ttl {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age >= ttl {
+            return None;
+        }
+    }
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+fn write<T: Serialize>(path: &Path, records: &[T]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // write to another file first, so that a concurrent run never reads a half written file
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(records)?)?;
+    fs::rename(tmp, path)
+}
+#[test]
+fn test_read_write() {
+    let dir = env::temp_dir().join(format!("ec2s-test-{}", std::process::id()));
+    let path = dir.join("r.json");
+    write(&path, &[1, 2]).unwrap();
+    assert_eq!(read::<i32>(&path, None), Some(vec![1, 2]));
+    assert_eq!(
+        read::<i32>(&path, Some(Duration::from_secs(60))),
+        Some(vec![1, 2])
+    );
+    assert_eq!(read::<i32>(&path, Some(Duration::ZERO)), None);
+    assert_eq!(read::<i32>(&dir.join("missing.json"), None), None);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// `name` of the cached records of all identities and regions. for shell completion
+pub fn cached_names(resource: &str) -> Vec<String> {
+    let base = match cache_dir() {
+        Some(d) => d,
+        None => return vec![],
+    };
+    let subdirs = |dir: &Path| -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect()
+    };
+    let mut names: Vec<String> = subdirs(&base)
+        .iter()
+        .flat_map(|identity| subdirs(identity))
+        .flat_map(|region| fs::read_dir(region).into_iter().flatten().flatten())
+        .map(|e| e.path())
+        .filter(|p| is_resource_file(p, resource))
+        .filter_map(|p| read::<serde_json::Value>(&p, None))
+        .flatten()
+        .filter_map(|r| r["name"].as_str().map(String::from))
+        .filter(|n| !n.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// `<resource>.json`, or a partial variant like `<resource>.untagged.json`
+fn is_resource_file(path: &Path, resource: &str) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix(resource))
+        .is_some_and(|rest| rest == ".json" || (rest.starts_with('.') && rest.ends_with(".json")))
+}
+#[test]
+fn test_is_resource_file() {
+    let base = Path::new("/c/key-x/us-east-1");
+    assert!(is_resource_file(
+        &base.join("target_groups.json"),
+        "target_groups"
+    ));
+    assert!(is_resource_file(
+        &base.join("target_groups.untagged.json"),
+        "target_groups"
+    ));
+    assert!(!is_resource_file(
+        &base.join("target_groups_x.json"),
+        "target_groups"
+    ));
+    assert!(!is_resource_file(
+        &base.join("instances.json"),
+        "target_groups"
+    ));
+}
@@ -0,0 +1,174 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// ~/.config/ec2s/config.toml. every value is a default, so flags always win
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub region: Option<String>,
+    pub profile: Option<String>,
+    // --output
+    pub output: Option<String>,
+    pub cache_ttl: Option<u64>,
+    pub instance: ResourceConfig,
+    pub target_group: ResourceConfig,
+    pub auto_scaling_group: ResourceConfig,
+    // arguments expanded from `@name`
+    pub queries: BTreeMap<String, SavedQuery>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceConfig {
+    // -T of info
+    pub tag_columns: Vec<String>,
+}
+
+impl ResourceConfig {
+    pub fn tag_columns(&self) -> Option<String> {
+        Some(self.tag_columns.join(",")).filter(|t| !t.is_empty())
+    }
+}
+
+// a string is split by whitespaces. use an array for values with spaces
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SavedQuery {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl SavedQuery {
+    fn args(&self) -> Vec<String> {
+        match self {
+            SavedQuery::Line(l) => l.split_whitespace().map(String::from).collect(),
+            SavedQuery::Args(a) => a.clone(),
+        }
+    }
+}
+
+// $EC2S_CONFIG, $XDG_CONFIG_HOME/ec2s/config.toml or ~/.config/ec2s/config.toml
+fn config_path() -> Option<PathBuf> {
+    let non_empty = |k: &str| env::var_os(k).filter(|v| !v.is_empty());
+    if let Some(p) = non_empty("EC2S_CONFIG") {
+        return Some(PathBuf::from(p));
+    }
+    let base = non_empty("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))?;
+    Some(base.join("ec2s").join("config.toml"))
+}
+
+// no config file is the same as an empty one
+pub fn load() -> Result<Config> {
+    let path = match config_path() {
+        Some(p) if p.exists() => p,
+        _ => return Ok(Config::default()),
+    };
+    parse(&fs::read_to_string(&path)?)
+        .map_err(|e| Error::Invalid(format!("invalid config {}: {}", path.display(), e)))
+}
+
+fn parse(s: &str) -> std::result::Result<Config, toml::de::Error> {
+    toml::from_str(s)
+}
+#[test]
+fn test_parse() {
+    let c = parse(
+        r#"
+region = "ap-northeast-1"
+output = "json"
+
+[instance]
+tag_columns = ["env", "role"]
+
+[queries]
+prod-api = "-q api -f tag:env=prod"
+web = ["-f", "tag:Name=web server"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(c.region.as_deref(), Some("ap-northeast-1"));
+    assert_eq!(c.instance.tag_columns().as_deref(), Some("env,role"));
+    assert_eq!(c.target_group.tag_columns(), None);
+    assert_eq!(
+        c.queries["prod-api"].args(),
+        vec!["-q", "api", "-f", "tag:env=prod"]
+    );
+    assert_eq!(c.queries["web"].args(), vec!["-f", "tag:Name=web server"]);
+    assert!(parse("unknown = 1").is_err());
+}
+
+impl Config {
+    // replace `@name` with the arguments of the saved query.
+    // `@` is left as is when `pending` tells that the arguments before it end with an option
+    // waiting for its value, e.g. `-q @x`
+    pub fn expand_queries(
+        &self,
+        args: Vec<String>,
+        pending: impl Fn(&[String]) -> bool,
+    ) -> Result<Vec<String>> {
+        let mut expanded: Vec<String> = vec![];
+        let mut args = args.into_iter();
+        for arg in args.by_ref() {
+            // the rest is the remote command, e.g. `git log @{u}`
+            if arg == "--" {
+                expanded.push(arg);
+                break;
+            }
+            let name = match arg.strip_prefix('@') {
+                Some(n) if !pending(&expanded) => n,
+                _ => {
+                    expanded.push(arg);
+                    continue;
+                }
+            };
+            match self.queries.get(name) {
+                Some(q) => expanded.extend(q.args()),
+                None => {
+                    return Err(Error::Invalid(format!(
+                        "unknown saved query '@{}'. saved queries are: {}",
+                        name,
+                        self.queries.keys().cloned().collect::<Vec<_>>().join(", ")
+                    )))
+                }
+            }
+        }
+        expanded.extend(args);
+        Ok(expanded)
+    }
+}
+#[test]
+fn test_expand_queries() {
+    let c = parse("[queries]\nprod-api = \"-q api -e\"").unwrap();
+    let args = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+    let pending = |prefix: &[String]| prefix.last().is_some_and(|p| p == "-q");
+    assert_eq!(
+        c.expand_queries(args("ec2s i info @prod-api -T env"), pending),
+        Ok(args("ec2s i info -q api -e -T env"))
+    );
+    assert_eq!(
+        c.expand_queries(args("ec2s i info -q @prod-api"), pending),
+        Ok(args("ec2s i info -q @prod-api"))
+    );
+    // a flag without a value
+    assert_eq!(
+        c.expand_queries(args("ec2s i info -e @prod-api"), pending),
+        Ok(args("ec2s i info -e -q api -e"))
+    );
+    // arguments of the remote command
+    assert_eq!(
+        c.expand_queries(
+            args("ec2s i ssh @prod-api -- git log @{u} @prod-api"),
+            pending
+        ),
+        Ok(args("ec2s i ssh -q api -e -- git log @{u} @prod-api"))
+    );
+    assert!(c
+        .expand_queries(args("ec2s i info @missing"), pending)
+        .is_err());
+}
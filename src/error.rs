@@ -0,0 +1,170 @@
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::{ErrorKind, ProvideErrorKind};
+use std::fmt;
+
+// errors which end a command. each kind has its own exit code for scripts
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // invalid query, filter or output format
+    Invalid(String),
+    // missing or expired credentials
+    Credentials(String),
+    Throttling(String),
+    AccessDenied(String),
+    NotFound(String),
+    // the search matched nothing
+    NoMatch(String),
+    // a command which needs exactly one resource matched several
+    Ambiguous(String),
+    // the interactive picker was closed without choosing
+    Cancelled,
+    // the remote command failed on some instances
+    Remote(String),
+    // the condition of a wait didn't hold in time
+    Timeout(String),
+    // any other api, network or terminal error
+    Api(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Invalid(_) | Error::Api(_) => 1,
+            Error::NoMatch(_) => 2,
+            Error::Ambiguous(_) => 3,
+            Error::Credentials(_) => 4,
+            Error::AccessDenied(_) => 5,
+            Error::Throttling(_) => 6,
+            Error::NotFound(_) => 7,
+            Error::Remote(_) => 8,
+            Error::Timeout(_) => 9,
+            Error::Cancelled => 130,
+        }
+    }
+
+    // classify a service error by its error code
+    fn from_code(code: Option<&str>, message: String) -> Error {
+        let code = match code {
+            Some(c) => c,
+            None => return Error::Api(message),
+        };
+        match code {
+            "AuthFailure"
+            | "ExpiredToken"
+            | "ExpiredTokenException"
+            | "InvalidClientTokenId"
+            | "RequestExpired"
+            | "SignatureDoesNotMatch"
+            | "UnrecognizedClientException" => Error::Credentials(message),
+            "AccessDenied" | "AccessDeniedException" | "UnauthorizedOperation" => {
+                Error::AccessDenied(message)
+            }
+            "Throttling" | "ThrottlingException" | "RequestLimitExceeded" => {
+                Error::Throttling(message)
+            }
+            c if c.contains("NotFound") => Error::NotFound(message),
+            _ => Error::Api(message),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Invalid(m)
+            | Error::Api(m)
+            | Error::Ambiguous(m)
+            | Error::Remote(m)
+            | Error::Timeout(m) => write!(f, "{}", m),
+            Error::NoMatch(m) => write!(f, "no {} matched", m),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Credentials(m) => write!(
+                f,
+                "unable to use the credentials. check the profile or log in again (e.g. `aws sso login`): {}",
+                m
+            ),
+            Error::Throttling(m) => write!(f, "request was throttled. retry later: {}", m),
+            Error::AccessDenied(m) => write!(f, "access denied: {}", m),
+            Error::NotFound(m) => write!(f, "not found: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// no match is an error so that scripts can tell it by the exit status
+pub fn found<T>(records: &[T], name: &str) -> Result<()> {
+    if records.is_empty() {
+        return Err(Error::NoMatch(name.to_string()));
+    }
+    Ok(())
+}
+
+// commands for a single resource need exactly one match
+pub fn single<'a, T>(records: &'a [T], name: &str) -> Result<&'a T> {
+    found(records, name)?;
+    if records.len() > 1 {
+        return Err(Error::Ambiguous(format!(
+            "{} {} matched. need to be narrowed to 1",
+            records.len(),
+            name
+        )));
+    }
+    Ok(&records[0])
+}
+
+impl From<String> for Error {
+    fn from(m: String) -> Self {
+        Error::Invalid(m)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Api(e.to_string())
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for Error
+where
+    E: ProvideErrorKind + std::error::Error + 'static,
+{
+    fn from(err: SdkError<E, R>) -> Self {
+        match err {
+            // signing fails when credentials can't be loaded
+            SdkError::ConstructionFailure(e) => Error::Credentials(e.to_string()),
+            SdkError::ServiceError { err, .. } => {
+                if err.retryable_error_kind() == Some(ErrorKind::ThrottlingError) {
+                    return Error::Throttling(err.to_string());
+                }
+                Error::from_code(err.code(), err.to_string())
+            }
+            e => Error::Api(e.to_string()),
+        }
+    }
+}
+#[test]
+fn test_from_code() {
+    let e = |code| Error::from_code(code, "m".to_string()).exit_code();
+    assert_eq!(e(Some("ExpiredToken")), 4);
+    assert_eq!(e(Some("UnauthorizedOperation")), 5);
+    assert_eq!(e(Some("RequestLimitExceeded")), 6);
+    assert_eq!(e(Some("InvalidInstanceID.NotFound")), 7);
+    assert_eq!(e(Some("TargetGroupNotFound")), 7);
+    assert_eq!(e(Some("ValidationError")), 1);
+    assert_eq!(e(None), 1);
+}
+#[test]
+fn test_single() {
+    assert_eq!(single(&[1], "instances"), Ok(&1));
+    assert_eq!(
+        single::<i32>(&[], "instances"),
+        Err(Error::NoMatch("instances".to_string()))
+    );
+    assert_eq!(
+        single(&[1, 2], "instances").map_err(|e| e.exit_code()),
+        Err(3)
+    );
+}
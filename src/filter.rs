@@ -0,0 +1,150 @@
+use crate::query::wildcard_match;
+use crate::utils::{tag_key, Record};
+use regex::Regex;
+use std::str::FromStr;
+
+// a filter expression. multiple filters are combined with AND.
+//   field=value  : equals, asterisk can be used as wildcard
+//   field~regex  : matches the regex
+//   field        : exists. tag is set or field is not empty
+//   !expr        : negation
+#[derive(Debug)]
+pub struct Filter {
+    pub negate: bool,
+    pub key: String,
+    pub condition: Condition,
+}
+
+#[derive(Debug)]
+pub enum Condition {
+    Exists,
+    Equal(String),
+    Regex(Regex),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negate, expr) = match s.strip_prefix('!') {
+            Some(e) => (true, e),
+            None => (false, s),
+        };
+        let (key, condition) = match expr.find(['=', '~']) {
+            Some(i) if &expr[i..i + 1] == "=" => {
+                (&expr[..i], Condition::Equal(expr[i + 1..].to_string()))
+            }
+            Some(i) => {
+                let r = Regex::new(&expr[i + 1..])
+                    .map_err(|e| format!("invalid regex in filter '{}': {}", s, e))?;
+                (&expr[..i], Condition::Regex(r))
+            }
+            None => (expr, Condition::Exists),
+        };
+        if key.is_empty() {
+            return Err(format!("filter '{}' has no field", s));
+        }
+        Ok(Filter {
+            negate,
+            key: key.to_string(),
+            condition,
+        })
+    }
+}
+
+impl Filter {
+    pub fn matches<T: Record>(&self, r: &T) -> Result<bool, String> {
+        let value = match tag_key(&self.key) {
+            Some(k) => r
+                .tags()
+                .iter()
+                .find(|t| t.key == k)
+                .map(|t| t.value.clone().unwrap_or_default()),
+            None => {
+                let v = r
+                    .attribute(&self.key)
+                    .ok_or_else(|| format!("unknown field '{}' in filter", self.key))?;
+                Some(v).filter(|v| !v.is_empty())
+            }
+        };
+        let matched = match (&self.condition, value) {
+            (Condition::Exists, v) => v.is_some(),
+            (Condition::Equal(pattern), v) => wildcard_match(pattern, &v.unwrap_or_default()),
+            (Condition::Regex(regex), v) => regex.is_match(&v.unwrap_or_default()),
+        };
+        Ok(matched != self.negate)
+    }
+}
+
+// all filters should match
+pub fn matches_all<T: Record>(filters: &[Filter], r: &T) -> Result<bool, String> {
+    for f in filters {
+        if !f.matches(r)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// keep records which match all filters
+pub fn apply<T: Record>(filters: &[Filter], records: Vec<T>) -> Result<Vec<T>, String> {
+    let mut result = vec![];
+    for r in records {
+        if matches_all(filters, &r)? {
+            result.push(r);
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_filter() {
+    use crate::utils::Tag;
+    struct R(Vec<Tag>);
+    impl Record for R {
+        fn attribute(&self, key: &str) -> Option<String> {
+            match key {
+                "state" => Some("running".to_string()),
+                "type" => Some("m5.large".to_string()),
+                "public_ip" => Some("".to_string()),
+                _ => None,
+            }
+        }
+        fn tags(&self) -> &[Tag] {
+            &self.0
+        }
+    }
+    let r = R(vec![
+        Tag {
+            key: "env".to_string(),
+            value: Some("prod".to_string()),
+        },
+        Tag {
+            key: "ignore-monitor".to_string(),
+            value: None,
+        },
+    ]);
+    let f = |s: &str| s.parse::<Filter>().unwrap().matches(&r);
+    assert_eq!(f("state=running"), Ok(true));
+    assert_eq!(f("state=stopped"), Ok(false));
+    assert_eq!(f("!state=stopped"), Ok(true));
+    assert_eq!(f("tag:env=prod"), Ok(true));
+    assert_eq!(f("tag:env=pr*"), Ok(true));
+    assert_eq!(f("tag:env=stg"), Ok(false));
+    assert_eq!(f("type~^m5"), Ok(true));
+    assert_eq!(f("type~^t3"), Ok(false));
+    assert_eq!(f("tag:ignore-monitor"), Ok(true));
+    assert_eq!(f("!tag:ignore-monitor"), Ok(false));
+    assert_eq!(f("!tag:missing"), Ok(true));
+    assert_eq!(f("public_ip"), Ok(false));
+    assert!(f("unknown=1").is_err());
+    assert!("=a".parse::<Filter>().is_err());
+    assert!("type~(".parse::<Filter>().is_err());
+
+    let filters: Vec<Filter> = vec![
+        "state=running".parse().unwrap(),
+        "tag:env=stg".parse().unwrap(),
+    ];
+    assert_eq!(matches_all(&filters, &r), Ok(false));
+    assert_eq!(matches_all(&filters[..1], &r), Ok(true));
+    assert_eq!(apply(&filters, vec![r]).map(|v| v.len()), Ok(0));
+}
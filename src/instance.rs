@@ -1,10 +1,33 @@
-use crate::awsutils::{config, GlobalOpt};
-use crate::utils::{get_values, print_table, split, Tag};
+use crate::awsutils::{
+    clients, datetime_rfc3339, merge_scoped, role_env, scope_role, GlobalOpt, Scope, ScopeColumns,
+    Scoped,
+};
+use crate::cache::{cached, Cached};
+use crate::error::{found, Error, Result};
+use crate::filter::{apply, Condition, Filter};
+use crate::output::{
+    custom_columns_rows, parse_custom_columns, print, print_without_counts, render_template,
+    OutputOpt,
+};
+use crate::picker::{choose_with_preview, is_interactive, pick_with_preview};
+use crate::query::{Matcher, Searchable};
+use crate::remote::{
+    batch_ssh_args, exec, exit_summary, run_prefixed, ssh_args, ssh_config_block, ssm_args, SshOpt,
+    SsmOpt,
+};
+use crate::utils::{get_values, split, tag_key, Record, Tag};
+use crate::watch::{watch, WatchOpt};
+use aws_sdk_ec2::model::Filter as ec2_filter;
 use aws_sdk_ec2::model::Tag as ec2_tag;
 use aws_sdk_ec2::Client;
+use futures::future::join_all;
 use itertools::Itertools;
-use std::process;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
 use structopt::StructOpt;
+use tokio::sync::Semaphore;
 
 #[derive(Debug, StructOpt)]
 pub enum InstanceOpt {
@@ -16,29 +39,125 @@ pub enum InstanceOpt {
     DnsName(SearchQueryOpt),
     #[structopt(about = "search instance basic info with query.")]
     Info(SearchInfoQueryOpt),
+    #[structopt(about = "choose an instance with fuzzy finder and print its fields.")]
+    Pick(PickOpt),
+    #[structopt(about = "ssh into the instance. choose one with fuzzy finder if several match.")]
+    Ssh(SshCommandOpt),
+    #[structopt(about = "start a session manager session on the instance.")]
+    Ssm(SsmCommandOpt),
+    #[structopt(about = "print ssh_config Host blocks of instances.")]
+    SshConfig(SshConfigOpt),
+    #[structopt(about = "run a command on all matched instances over ssh concurrently.")]
+    Exec(ExecOpt),
 }
 
 #[derive(Debug, StructOpt)]
-pub struct SearchQueryOpt {
+pub struct ExecOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(flatten)]
+    ssh: SshOpt,
     #[structopt(
-        short = "q",
+        short = "p",
         long,
-        help = "ambiguous search with asterisk on tag name. if set comma, search OR"
+        default_value = "10",
+        help = "the number of instances to run the command on at once"
     )]
-    query: String,
+    parallel: usize,
+    #[structopt(last = true, required = true, help = "the command to run")]
+    command: Vec<String>,
 }
+
 #[derive(Debug, StructOpt)]
-pub struct SearchInfoQueryOpt {
+pub struct SshConfigOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    #[structopt(
+        short = "w",
+        long,
+        help = "write to the file instead of stdout. the whole file is rewritten on each run, \
+    so include it from ~/.ssh/config"
+    )]
+    write: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SsmCommandOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(flatten)]
+    ssm: SsmOpt,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SshCommandOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(flatten)]
+    ssh: SshOpt,
+    #[structopt(last = true, help = "the command to run instead of a login shell")]
+    command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PickOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(
+        short = "p",
+        long = "print",
+        default_value = "id",
+        help = "comma separated fields of the chosen instance to print, separated by tab. e.g. id,private_ip"
+    )]
+    print: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SearchQueryOpt {
     #[structopt(
         short = "q",
         long,
         help = "ambiguous search with asterisk on tag name. if set comma, search OR"
     )]
-    query: String,
+    query: Option<String>,
+    #[structopt(
+        short = "e",
+        long,
+        conflicts_with = "regex",
+        help = "exact search instead of ambiguous search. asterisk can be used as wildcard"
+    )]
+    exact: bool,
+    #[structopt(
+        short = "r",
+        long,
+        help = "regex search on tag name, id, DNS name and tags. use | instead of comma for OR"
+    )]
+    regex: bool,
+    #[structopt(
+        short = "f",
+        long = "filter",
+        number_of_values = 1,
+        help = "filter with field expressions. if set multiple, search AND.
+    field=value (asterisk as wildcard), field~regex, field (exists), !expr (not)
+    e.g. -f state=running -f tag:env=prod -f 'type~^m5' -f '!tag:ignore-monitor'"
+    )]
+    filters: Vec<Filter>,
+}
+#[derive(Debug, StructOpt)]
+pub struct SearchInfoQueryOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
     #[structopt(
         short = "o",
+        name = "format",
         help = "Output format. One of:
-    name|wide"
+    name|wide|hosts[=<suffix>]|template=<template>|custom-columns=<spec>
+    e.g. template='{{id}} {{private_ip}} {{tag.env}}' or custom-columns=ID:id,IP:private_ip,Env:tag.env
+    hosts prints /etc/hosts lines. with suffix, `name.<suffix>` is added as an alias
+    fields: id, name, status, instance_type, az, lifecycle, private_ip, public_ip,
+    private_dns, public_dns, launch_time, vpc_id, region, profile, account, tag.<key>"
     )]
     output: Option<String>,
     #[structopt(
@@ -50,19 +169,41 @@ pub struct SearchInfoQueryOpt {
     tag_columns: Option<String>,
     #[structopt(long = "show-all-tags", help = "Show all tags.")]
     show_all_tags: bool,
+    #[structopt(
+        long = "public-ip",
+        help = "use public IPs instead of private IPs for -o hosts"
+    )]
+    public_ip: bool,
+    #[structopt(flatten)]
+    watch: WatchOpt,
 }
 
-pub async fn matcher(global_opt: GlobalOpt, opt: InstanceOpt) {
-    let cli = Client::new(&config(global_opt).await);
+pub async fn matcher(mut global_opt: GlobalOpt, opt: InstanceOpt) -> Result<()> {
+    // every redraw fetches again
+    if let InstanceOpt::Info(o) = &opt {
+        global_opt.cache.refresh |= o.watch.is_set();
+    }
+    let clis = clients::<Client>(&global_opt).await?;
+    let output = &global_opt.output;
     match opt {
-        InstanceOpt::Info(opt) => info(&cli, opt).await,
-        InstanceOpt::InstanceIds(opt) => instance_ids(&cli, opt).await,
-        InstanceOpt::Ips(opt) => instance_ips(&cli, opt).await,
-        InstanceOpt::DnsName(opt) => instance_private_dns(&cli, opt).await,
+        InstanceOpt::Info(mut opt) => {
+            opt.tag_columns = opt.tag_columns.or(global_opt.tag_columns);
+            let output = &opt.watch.output(output);
+            watch(&opt.watch, || info(&clis, output, &opt)).await
+        }
+        InstanceOpt::InstanceIds(opt) => instance_ids(&clis, output, opt).await,
+        InstanceOpt::Ips(opt) => instance_ips(&clis, output, opt).await,
+        InstanceOpt::DnsName(opt) => instance_private_dns(&clis, output, opt).await,
+        InstanceOpt::Pick(opt) => pick(&clis, opt).await,
+        InstanceOpt::Ssh(opt) => ssh(&clis, opt).await,
+        InstanceOpt::Ssm(opt) => ssm(&clis, &global_opt, opt).await,
+        InstanceOpt::SshConfig(opt) => ssh_config(&clis, opt).await,
+        InstanceOpt::Exec(opt) => exec_all(&clis, opt).await,
     }
 }
-async fn info(cli: &Client, opt: SearchInfoQueryOpt) {
-    let instances = get_instances(cli, &SearchQueryOpt { query: opt.query }).await;
+async fn info(clis: &[Scoped<Client>], output: &OutputOpt, opt: &SearchInfoQueryOpt) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let instances = get_instances(clis, &opt.query).await?;
     let tag_column: Vec<String> = if opt.show_all_tags {
         instances
             .iter()
@@ -71,142 +212,445 @@ async fn info(cli: &Client, opt: SearchInfoQueryOpt) {
             .collect()
     } else {
         opt.tag_columns
-            .map(|t| split(&*t, true))
+            .as_ref()
+            .map(|t| split(t, true))
             .unwrap_or_default()
     };
     match opt.output.as_deref() {
         Some("name") => {
-            let rows: Vec<Vec<String>> = instances.into_iter().map(|i| vec![i.name]).collect();
-            print_table(vec![], rows);
+            let rows: Vec<Vec<String>> = instances.iter().map(|i| vec![i.name.clone()]).collect();
+            print_without_counts(output, &instances, vec![], rows)?;
         }
         Some("wide") => {
             let rows: Vec<Vec<String>> = instances
-                .into_iter()
+                .iter()
                 .map(|i| {
                     let r = get_values(&i.tags, &tag_column);
+                    let row = vec![
+                        i.id.clone(),
+                        i.name.clone(),
+                        i.status.clone(),
+                        i.instance_type.clone(),
+                        i.private_dns.clone(),
+                        i.private_ip.clone(),
+                        i.az.clone(),
+                        i.lifecycle.clone(),
+                    ];
+                    columns.row(&i.scope, row.into_iter().chain(r).collect())
+                })
+                .collect();
+            print_without_counts(
+                output,
+                &instances,
+                columns.header(
                     vec![
-                        i.id,
-                        i.name,
-                        i.status,
-                        i.instance_type,
-                        i.private_dns,
-                        i.private_ip,
-                        i.az,
-                        i.lifecycle,
+                        "ID".to_string(),
+                        "Name".to_string(),
+                        "Status".to_string(),
+                        "Type".to_string(),
+                        "PrivateDNS".to_string(),
+                        "PrivateIP".to_string(),
+                        "AZ".to_string(),
+                        "LifeCycle".to_string(),
                     ]
                     .into_iter()
-                    .chain(r)
-                    .collect()
-                })
-                .collect();
-            print_table(
-                vec![
-                    "ID".to_string(),
-                    "Name".to_string(),
-                    "Status".to_string(),
-                    "Type".to_string(),
-                    "PrivateDNS".to_string(),
-                    "PrivateIP".to_string(),
-                    "AZ".to_string(),
-                    "LifeCycle".to_string(),
-                ]
-                .into_iter()
-                .chain(tag_column)
-                .collect(),
+                    .chain(tag_column)
+                    .collect(),
+                ),
                 rows,
-            );
+            )?;
         }
         None => {
-            let len = instances.len();
             let rows: Vec<Vec<String>> = instances
-                .into_iter()
+                .iter()
                 .map(|i| {
                     let r = get_values(&i.tags, &tag_column);
-                    vec![i.id, i.name, i.status, i.instance_type]
-                        .into_iter()
-                        .chain(r)
-                        .collect()
+                    let row = vec![
+                        i.id.clone(),
+                        i.name.clone(),
+                        i.status.clone(),
+                        i.instance_type.clone(),
+                    ];
+                    columns.row(&i.scope, row.into_iter().chain(r).collect())
                 })
                 .collect();
-            print_table(
-                vec![
-                    "ID".to_string(),
-                    "Name".to_string(),
-                    "Status".to_string(),
-                    "Type".to_string(),
-                ]
-                .into_iter()
-                .chain(tag_column)
-                .collect(),
+            print(
+                output,
+                &instances,
+                columns.header(
+                    vec![
+                        "ID".to_string(),
+                        "Name".to_string(),
+                        "Status".to_string(),
+                        "Type".to_string(),
+                    ]
+                    .into_iter()
+                    .chain(tag_column)
+                    .collect(),
+                ),
+                rows,
+            )?;
+        }
+        Some(h) if h == "hosts" || h.starts_with("hosts=") => {
+            let suffix = h.trim_start_matches("hosts").trim_start_matches('=');
+            let field = if opt.public_ip {
+                "public_ip"
+            } else {
+                "private_ip"
+            };
+            for (i, name) in instances.iter().zip(host_names(&instances)) {
+                match address(i, field) {
+                    Ok(ip) => println!("{}", hosts_line(&ip, &name, suffix)),
+                    Err(e) => eprintln!("Warning: {}. skipped", e),
+                }
+            }
+        }
+        Some(t) if t.starts_with("template=") => {
+            let template = t.trim_start_matches("template=");
+            for i in instances.iter() {
+                println!("{}", render_template(template, i)?);
+            }
+        }
+        Some(c) if c.starts_with("custom-columns=") => {
+            let columns = parse_custom_columns(c.trim_start_matches("custom-columns="))?;
+            let rows = custom_columns_rows(&columns, &instances)?;
+            print(
+                output,
+                &instances,
+                columns.into_iter().map(|(h, _)| h).collect(),
                 rows,
-            );
-            println!("counts: {}", len);
+            )?;
         }
         Some(a) => {
-            eprintln!(
-                "Error: unable to match a printer suitable for the output format '{}'. \
-             allow formats are: name,wide",
+            return Err(Error::Invalid(format!(
+                "unable to match a printer suitable for the output format '{}'. \
+             allow formats are: name,wide,hosts,template=,custom-columns=",
                 a
-            );
-            process::exit(1);
+            )));
         }
     }
+    found(&instances, "instances")
 }
 
-async fn instance_ids(cli: &Client, opt: SearchQueryOpt) {
-    let instances = get_instances(cli, &opt).await;
-    let len = instances.len();
-    let rows: Vec<Vec<String>> = instances.into_iter().map(|i| vec![i.id, i.name]).collect();
-    print_table(vec!["ID".to_string(), "Name".to_string()], rows);
-    println!("counts: {}", len);
+async fn instance_ids(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    opt: SearchQueryOpt,
+) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let instances = get_instances(clis, &opt).await?;
+    let rows: Vec<Vec<String>> = instances
+        .iter()
+        .map(|i| columns.row(&i.scope, vec![i.id.clone(), i.name.clone()]))
+        .collect();
+    print(
+        output,
+        &instances,
+        columns.header(vec!["ID".to_string(), "Name".to_string()]),
+        rows,
+    )?;
+    found(&instances, "instances")
 }
 
-async fn instance_ips(cli: &Client, opt: SearchQueryOpt) {
-    let instances = get_instances(cli, &opt).await;
-    let len = instances.len();
+async fn instance_ips(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    opt: SearchQueryOpt,
+) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let instances = get_instances(clis, &opt).await?;
     let rows: Vec<Vec<String>> = instances
-        .into_iter()
-        .map(|i| vec![i.private_ip, i.public_ip.unwrap_or_default(), i.id, i.name])
+        .iter()
+        .map(|i| {
+            columns.row(
+                &i.scope,
+                vec![
+                    i.private_ip.clone(),
+                    i.public_ip.clone().unwrap_or_default(),
+                    i.id.clone(),
+                    i.name.clone(),
+                ],
+            )
+        })
         .collect();
-    print_table(
-        vec![
+    print(
+        output,
+        &instances,
+        columns.header(vec![
             "Private IP".to_string(),
             "Public IP".to_string(),
             "ID".to_string(),
             "Name".to_string(),
-        ],
+        ]),
         rows,
-    );
-
-    println!("counts: {}", len);
+    )?;
+    found(&instances, "instances")
 }
-async fn instance_private_dns(cli: &Client, opt: SearchQueryOpt) {
-    let instances = get_instances(cli, &opt).await;
-    let len = instances.len();
+async fn instance_private_dns(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    opt: SearchQueryOpt,
+) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let instances = get_instances(clis, &opt).await?;
     let rows: Vec<Vec<String>> = instances
-        .into_iter()
+        .iter()
         .map(|i| {
-            vec![
-                i.private_dns,
-                i.public_dns.unwrap_or_default(),
-                i.id,
-                i.name,
-            ]
+            columns.row(
+                &i.scope,
+                vec![
+                    i.private_dns.clone(),
+                    i.public_dns.clone().unwrap_or_default(),
+                    i.id.clone(),
+                    i.name.clone(),
+                ],
+            )
         })
         .collect();
-    print_table(
-        vec![
+    print(
+        output,
+        &instances,
+        columns.header(vec![
             "Private DNS".to_string(),
             "Public DNS".to_string(),
             "ID".to_string(),
             "Name".to_string(),
-        ],
+        ]),
         rows,
+    )?;
+    found(&instances, "instances")
+}
+
+async fn pick(clis: &[Scoped<Client>], opt: PickOpt) -> Result<()> {
+    if !is_interactive() {
+        return Err(Error::Invalid("pick needs a terminal".to_string()));
+    }
+    let instances = get_instances(clis, &opt.query).await?;
+    found(&instances, "instances")?;
+    let fields = split(&opt.print, true);
+    // check fields before opening the finder
+    if let Some(f) = fields.iter().find(|f| instances[0].field(f).is_none()) {
+        return Err(Error::Invalid(format!("unknown field '{}'", f)));
+    }
+    let items = labels(&instances);
+    let i = match pick_with_preview("instances", &items, |i| preview(&instances[i]))? {
+        Some(i) => &instances[i],
+        None => return Err(Error::Cancelled),
+    };
+    let values: Vec<String> = fields
+        .iter()
+        .map(|f| i.field(f).unwrap_or_default())
+        .collect();
+    println!("{}", values.join("\t"));
+    Ok(())
+}
+
+async fn ssh(clis: &[Scoped<Client>], opt: SshCommandOpt) -> Result<()> {
+    let instances = get_instances(clis, &opt.query).await?;
+    let i = choose_instance(&instances)?;
+    let host = address(i, &opt.ssh.address)?;
+    exec(Command::new("ssh").args(ssh_args(&opt.ssh, &host, &opt.command)))
+}
+
+// needs aws cli and its session manager plugin
+async fn ssm(clis: &[Scoped<Client>], global_opt: &GlobalOpt, opt: SsmCommandOpt) -> Result<()> {
+    let instances = get_instances(clis, &opt.query).await?;
+    let i = choose_instance(&instances)?;
+    let mut command = Command::new("aws");
+    let mut profile = i.scope.profile.as_str();
+    // the aws cli can't assume the role by itself, so it gets the credentials of the role instead of the profile
+    if let Some(role) = scope_role(&global_opt.assume_roles, &i.scope) {
+        let p = Some(profile).filter(|p| !p.is_empty());
+        command.envs(role_env(&i.scope.region, p, role).await?);
+        command.env_remove("AWS_PROFILE");
+        profile = "";
+    }
+    let endpoint = global_opt
+        .endpoint_url
+        .as_ref()
+        .map(|u| u.to_string())
+        .unwrap_or_default();
+    let args = ssm_args(&opt.ssm, &i.id, &i.scope.region, profile, &endpoint);
+    exec(command.args(args))
+}
+
+async fn ssh_config(clis: &[Scoped<Client>], opt: SshConfigOpt) -> Result<()> {
+    let instances = get_instances(clis, &opt.query).await?;
+    // a typo in the query must not empty the written file
+    found(&instances, "instances")?;
+    let mut blocks = vec![];
+    for (i, alias) in instances.iter().zip(host_names(&instances)) {
+        match address(i, &opt.ssh.address) {
+            Ok(host) => blocks.push(ssh_config_block(&alias, &host, &opt.ssh)),
+            Err(e) => eprintln!("Warning: {}. skipped", e),
+        }
+    }
+    // neither must instances without the address
+    found(&blocks, &format!("instances with {}", opt.ssh.address))?;
+    let config = blocks.join("\n");
+    match &opt.write {
+        Some(path) => {
+            let header =
+                "# managed by ec2s. this file is rewritten on each run, so don't edit it\n\n";
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, header.to_string() + &config)?;
+            eprintln!("wrote {} hosts to {}", blocks.len(), path.display());
+        }
+        None => print!("{}", config),
+    }
+    Ok(())
+}
+
+async fn exec_all(clis: &[Scoped<Client>], opt: ExecOpt) -> Result<()> {
+    if opt.parallel == 0 {
+        return Err(Error::Invalid("--parallel must be 1 or more".to_string()));
+    }
+    let instances = get_instances(clis, &opt.query).await?;
+    found(&instances, "instances")?;
+    let mut targets = vec![];
+    for (i, name) in instances.iter().zip(host_names(&instances)) {
+        match address(i, &opt.ssh.address) {
+            Ok(host) => targets.push((name, batch_ssh_args(&opt.ssh, &host, &opt.command))),
+            Err(e) => eprintln!("Warning: {}. skipped", e),
+        }
+    }
+    let width = targets.iter().map(|(n, _)| n.len()).max().unwrap_or(0);
+    let limit = Semaphore::new(opt.parallel);
+    let runs = targets.iter().map(|(name, args)| {
+        let mut command = tokio::process::Command::new("ssh");
+        command.args(args);
+        run_prefixed(format!("{:<width$}", name, width = width), command, &limit)
+    });
+    let codes = join_all(runs).await;
+    let results: Vec<(String, i32)> = targets
+        .into_iter()
+        .zip(codes)
+        .map(|((name, _), code)| match code {
+            Ok(c) => (name, c),
+            Err(e) => {
+                eprintln!("{:<width$} | Error: {}", name, e, width = width);
+                (name, 255)
+            }
+        })
+        .collect();
+    exit_summary(&results)
+}
+
+// Name tags as host names. duplicated or empty names are told apart by instance ids
+pub(crate) fn host_names(instances: &[Instance]) -> Vec<String> {
+    let counts = instances.iter().counts_by(|i| i.name.as_str());
+    instances
+        .iter()
+        .map(|i| {
+            let name = i.name.replace(char::is_whitespace, "-");
+            if name.is_empty() {
+                i.id.clone()
+            } else if counts[i.name.as_str()] > 1 {
+                format!("{}-{}", name, i.id)
+            } else {
+                name
+            }
+        })
+        .collect()
+}
+
+// a line of /etc/hosts
+fn hosts_line(ip: &str, name: &str, suffix: &str) -> String {
+    if suffix.is_empty() {
+        format!("{}  {}", ip, name)
+    } else {
+        format!("{}  {}  {}.{}", ip, name, name, suffix)
+    }
+}
+#[test]
+fn test_hosts_line() {
+    assert_eq!(hosts_line("10.0.0.1", "api", ""), "10.0.0.1  api");
+    assert_eq!(
+        hosts_line("10.0.0.1", "api", "prod.internal"),
+        "10.0.0.1  api  api.prod.internal"
     );
-    println!("counts: {}", len);
 }
 
-struct Instance {
+// one instance to connect to. ask the user when several match
+fn choose_instance(instances: &[Instance]) -> Result<&Instance> {
+    let widths = label_widths(instances);
+    choose_with_preview(instances, "instances", |i| label(i, widths), preview)
+}
+
+pub(crate) fn address(i: &Instance, field: &str) -> Result<String> {
+    match i.field(field).filter(|a| !a.is_empty()) {
+        Some(a) => Ok(a),
+        None => Err(Error::Invalid(format!("{} has no {}", i.id, field))),
+    }
+}
+
+// aligned lines for the fuzzy finder
+fn labels(instances: &[Instance]) -> Vec<String> {
+    let widths = label_widths(instances);
+    instances.iter().map(|i| label(i, widths)).collect()
+}
+
+// widths of name and private ip
+fn label_widths(instances: &[Instance]) -> (usize, usize) {
+    let width = |f: fn(&Instance) -> &str| instances.iter().map(|i| f(i).len()).max().unwrap_or(0);
+    (width(|i| &i.name), width(|i| &i.private_ip))
+}
+
+fn label(i: &Instance, (name_width, ip_width): (usize, usize)) -> String {
+    format!(
+        "{}  {:<nw$}  {:<iw$}  {}  {}  {}",
+        i.id,
+        i.name,
+        i.private_ip,
+        i.status,
+        i.instance_type,
+        i.scope.region,
+        nw = name_width,
+        iw = ip_width
+    )
+}
+
+// all fields and tags of the instance
+fn preview(i: &Instance) -> Vec<String> {
+    let fields = FIELDS.iter().filter_map(|f| {
+        let v = i.attribute(f).unwrap_or_default();
+        Some(format!("{:<13} {}", f, v)).filter(|_| !v.is_empty())
+    });
+    let tags = i
+        .tags
+        .iter()
+        .sorted_by(|a, b| a.key.cmp(&b.key))
+        .map(|t| format!("  {}={}", t.key, t.value.clone().unwrap_or_default()));
+    fields
+        .chain(std::iter::once(String::new()))
+        .chain(std::iter::once("tags:".to_string()))
+        .chain(tags)
+        .collect()
+}
+
+// fields of Instance except tags
+pub(crate) const FIELDS: [&str; 15] = [
+    "id",
+    "name",
+    "status",
+    "instance_type",
+    "az",
+    "lifecycle",
+    "private_ip",
+    "public_ip",
+    "private_dns",
+    "public_dns",
+    "launch_time",
+    "vpc_id",
+    "region",
+    "profile",
+    "account",
+];
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Instance {
     id: String,
     name: String,
     instance_type: String,
@@ -217,96 +661,236 @@ struct Instance {
     public_ip: Option<String>,
     private_dns: String,
     public_dns: Option<String>,
+    launch_time: Option<String>,
+    vpc_id: Option<String>,
     tags: Vec<Tag>,
+    #[serde(flatten)]
+    scope: Scope,
+}
+
+impl Cached for Instance {
+    fn set_scope(&mut self, scope: &Scope) {
+        self.scope = scope.clone();
+    }
+}
+
+impl Record for Instance {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "id" => self.id.clone(),
+            "name" => self.name.clone(),
+            "instance_type" | "type" => self.instance_type.clone(),
+            "status" | "state" => self.status.clone(),
+            "az" => self.az.clone(),
+            "lifecycle" => self.lifecycle.clone(),
+            "private_ip" => self.private_ip.clone(),
+            "public_ip" => self.public_ip.clone().unwrap_or_default(),
+            "private_dns" => self.private_dns.clone(),
+            "public_dns" => self.public_dns.clone().unwrap_or_default(),
+            "launch_time" => self.launch_time.clone().unwrap_or_default(),
+            "vpc_id" | "vpc" => self.vpc_id.clone().unwrap_or_default(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
+    }
+    fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
 }
 
-async fn get_instances(client: &Client, opt: &SearchQueryOpt) -> Vec<Instance> {
+pub(crate) async fn get_instances(
+    clis: &[Scoped<Client>],
+    opt: &SearchQueryOpt,
+) -> Result<Vec<Instance>> {
+    let matcher = Matcher::new(opt.query.as_deref(), opt.exact, opt.regex)?;
+    let filters: Vec<ec2_filter> = server_filters(&opt.filters)
+        .into_iter()
+        .map(|(name, value)| ec2_filter::builder().name(name).values(value).build())
+        .collect();
+    let vector = join_all(clis.iter().map(|c| scoped_instances(c, &filters))).await;
+    // server side filters only narrow the results. all filters are checked again here
+    let instances = merge_scoped(clis, vector)?
+        .into_iter()
+        .filter(|i| matcher.matches(i))
+        .collect();
+    Ok(apply(&opt.filters, instances)?)
+}
+// cached results are narrowed by the filters afterwards, so only the results without filters are cached
+async fn scoped_instances(cli: &Scoped<Client>, filters: &[ec2_filter]) -> Result<Vec<Instance>> {
+    let fetch = fetch_instances(cli, filters);
+    cached(
+        &cli.cache,
+        &cli.scope,
+        "instances",
+        filters.is_empty(),
+        fetch,
+    )
+    .await
+}
+async fn fetch_instances(cli: &Scoped<Client>, filters: &[ec2_filter]) -> Result<Vec<Instance>> {
     let mut m: Option<String> = None;
     let mut vector: Vec<Instance> = vec![];
     loop {
-        let (mut v, mark) = instances(client, &m).await;
+        let (mut v, mark) = instances(cli, &m, filters).await?;
         m = mark;
         vector.append(&mut v);
         if m.is_none() {
             break;
         }
     }
-    vector.into_iter().filter(|i| search(i, opt)).collect()
+    Ok(vector)
 }
-async fn instances(cli: &Client, marker: &Option<String>) -> (Vec<Instance>, Option<String>) {
-    match cli
+async fn instances(
+    cli: &Scoped<Client>,
+    marker: &Option<String>,
+    filters: &[ec2_filter],
+) -> Result<(Vec<Instance>, Option<String>)> {
+    let res = cli
+        .client
         .describe_instances()
         .set_next_token(marker.clone())
+        .set_filters(Some(filters.to_vec()).filter(|f| !f.is_empty()))
         .send()
-        .await
-    {
-        Ok(res) => {
-            let instances = res
-                .reservations
-                .into_iter()
-                .flat_map(|v| v.into_iter().flat_map(|r| r.instances.unwrap_or_default()));
-            (
-                instances
-                    .map(|i| Instance {
-                        name: name(&i.tags),
-                        id: i.instance_id.unwrap_or_default(),
-                        status: i
-                            .state
-                            .and_then(|i| i.name.map(|n| n.as_str().to_string()))
-                            .unwrap_or_default(),
-                        lifecycle: i
-                            .instance_lifecycle
-                            .map(|i| i.as_str().to_string())
-                            .unwrap_or_else(|| "normal".to_string()),
-                        az: i
-                            .placement
-                            .and_then(|p| p.availability_zone)
-                            .unwrap_or_default(),
-                        instance_type: i
-                            .instance_type
-                            .map(|i| i.as_str().to_string())
-                            .unwrap_or_default(),
-                        private_ip: i.private_ip_address.unwrap_or_default(),
-                        public_ip: i.public_ip_address,
-                        private_dns: i.private_dns_name.unwrap_or_default(),
-                        public_dns: i.public_dns_name,
-                        tags: i
-                            .tags
-                            .map(|vt| {
-                                vt.into_iter()
-                                    .map(|t| Tag {
-                                        key: t.key.unwrap_or_default(),
-                                        value: t.value,
-                                    })
-                                    .collect()
+        .await?;
+    let instances = res
+        .reservations
+        .into_iter()
+        .flat_map(|v| v.into_iter().flat_map(|r| r.instances.unwrap_or_default()));
+    Ok((
+        instances
+            .map(|i| Instance {
+                name: name(&i.tags),
+                id: i.instance_id.unwrap_or_default(),
+                status: i
+                    .state
+                    .and_then(|i| i.name.map(|n| n.as_str().to_string()))
+                    .unwrap_or_default(),
+                lifecycle: i
+                    .instance_lifecycle
+                    .map(|i| i.as_str().to_string())
+                    .unwrap_or_else(|| "normal".to_string()),
+                az: i
+                    .placement
+                    .and_then(|p| p.availability_zone)
+                    .unwrap_or_default(),
+                instance_type: i
+                    .instance_type
+                    .map(|i| i.as_str().to_string())
+                    .unwrap_or_default(),
+                private_ip: i.private_ip_address.unwrap_or_default(),
+                public_ip: i.public_ip_address,
+                private_dns: i.private_dns_name.unwrap_or_default(),
+                public_dns: i.public_dns_name,
+                launch_time: i.launch_time.map(datetime_rfc3339),
+                vpc_id: i.vpc_id,
+                scope: cli.scope.clone(),
+                tags: i
+                    .tags
+                    .map(|vt| {
+                        vt.into_iter()
+                            .map(|t| Tag {
+                                key: t.key.unwrap_or_default(),
+                                value: t.value,
                             })
-                            .unwrap_or_default(),
+                            .collect()
                     })
-                    .collect::<Vec<_>>(),
-                res.next_token,
-            )
-        }
-        Err(err) => panic!("{}", err.to_string()),
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>(),
+        res.next_token,
+    ))
+}
+
+// DescribeInstances filter names for instance fields
+fn ec2_filter_name(key: &str) -> Option<String> {
+    if let Some(k) = tag_key(key) {
+        return Some(format!("tag:{}", k));
     }
+    let name = match key {
+        "id" => "instance-id",
+        "name" => "tag:Name",
+        "status" | "state" => "instance-state-name",
+        "instance_type" | "type" => "instance-type",
+        "az" => "availability-zone",
+        "vpc_id" | "vpc" => "vpc-id",
+        "private_ip" => "private-ip-address",
+        "private_dns" => "private-dns-name",
+        _ => return None,
+    };
+    Some(name.to_string())
 }
 
-fn search(i: &Instance, opt: &SearchQueryOpt) -> bool {
-    for q in opt.query.split(',') {
-        if i.name.contains(q)
-            || i.id.contains(q)
-            || i.private_dns.contains(q)
-            || i.tags
-                .iter()
-                .any(|t| t.key.contains(q) || t.value.as_ref().filter(|v| v.contains(q)).is_some())
-        {
-            return true;
+// filters which DescribeInstances can evaluate, as (filter name, value).
+// negations and regexes are left to the client side.
+fn server_filters(filters: &[Filter]) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = vec![];
+    for f in filters.iter().filter(|f| !f.negate) {
+        let pair = match (&f.condition, tag_key(&f.key)) {
+            // `?` is a wildcard only for EC2, so those values are matched on the client.
+            // so are values matching an empty string, which EC2 never matches with a missing tag
+            (Condition::Equal(v), _) if !v.contains('?') && !v.chars().all(|c| c == '*') => {
+                ec2_filter_name(&f.key).map(|n| (n, v.clone()))
+            }
+            (Condition::Exists, Some(k)) => Some(("tag-key".to_string(), k.to_string())),
+            _ => None,
+        };
+        // keep the first one for each name not to mix AND and OR of the same filter
+        if let Some((name, value)) = pair {
+            if !result.iter().any(|(n, _)| n == &name) {
+                result.push((name, value));
+            }
         }
     }
-    false
+    result
 }
 #[test]
-fn test_search() {
-    let i = Instance {
+fn test_server_filters() {
+    let filters: Vec<Filter> = [
+        "state=running",
+        "name=*api*",
+        "tag:env=prod",
+        "type~^m5",
+        "!az=ap-northeast-1a",
+        "tag:ignore-monitor",
+        "state=stopped",
+        "lifecycle=spot",
+        "vpc=vpc-1?",
+        "private_dns=*",
+        "tag:team=",
+    ]
+    .iter()
+    .map(|f| f.parse().unwrap())
+    .collect();
+    assert_eq!(
+        server_filters(&filters),
+        vec![
+            ("instance-state-name".to_string(), "running".to_string()),
+            ("tag:Name".to_string(), "*api*".to_string()),
+            ("tag:env".to_string(), "prod".to_string()),
+            ("tag-key".to_string(), "ignore-monitor".to_string()),
+        ]
+    );
+}
+
+impl Searchable for Instance {
+    fn search_keys(&self) -> Vec<&str> {
+        vec![
+            self.name.as_str(),
+            self.id.as_str(),
+            self.private_dns.as_str(),
+        ]
+        .into_iter()
+        .chain(
+            self.tags
+                .iter()
+                .flat_map(|t| std::iter::once(t.key.as_str()).chain(t.value.as_deref())),
+        )
+        .collect()
+    }
+}
+#[cfg(test)]
+fn sample_instance() -> Instance {
+    Instance {
         id: "i-2342545".to_string(),
         name: "api".to_string(),
         instance_type: "t3.micro".to_string(),
@@ -317,65 +901,41 @@ fn test_search() {
         private_dns: "192.168.0.1.ap-northeast-1".to_string(),
         public_ip: None,
         public_dns: None,
+        launch_time: None,
+        vpc_id: Some("vpc-1234".to_string()),
+        scope: Scope::default(),
         tags: vec![Tag {
             key: "env".to_string(),
             value: Some("production".to_string()),
         }],
-    };
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "234254".to_string()
-            }
-        ),
-        true
-    );
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "api,test".to_string()
-            }
-        ),
-        true
-    );
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "test".to_string()
-            }
-        ),
-        false
-    );
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "192.168".to_string()
-            }
-        ),
-        true
-    );
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "server,test".to_string()
-            }
-        ),
-        false
-    );
-    assert_eq!(
-        search(
-            &i,
-            &SearchQueryOpt {
-                query: "production".to_string()
-            }
-        ),
-        true
-    );
+    }
+}
+#[test]
+fn test_search() {
+    let i = sample_instance();
+    let search = |q: &str, exact: bool| Matcher::new(Some(q), exact, false).unwrap().matches(&i);
+    assert!(search("234254", false));
+    assert!(search("api,test", false));
+    assert!(!search("test", false));
+    assert!(search("192.168", false));
+    assert!(!search("server,test", false));
+    assert!(search("production", false));
+    assert!(search("api", true));
+    assert!(!search("ap", true));
+    assert!(search("prod*", true));
+    assert!(Matcher::new(Some("^i-\\d+$"), false, true)
+        .unwrap()
+        .matches(&i));
+}
+#[test]
+fn test_instance_field() {
+    let i = sample_instance();
+    assert_eq!(i.field("id"), Some("i-2342545".to_string()));
+    assert_eq!(i.field("type"), Some("t3.micro".to_string()));
+    assert_eq!(i.field("public_ip"), Some("".to_string()));
+    assert_eq!(i.field("tag.env"), Some("production".to_string()));
+    assert_eq!(i.field("tag.missing"), Some("".to_string()));
+    assert_eq!(i.field("unknown"), None);
 }
 
 // extract Tag Name from instance
@@ -397,3 +957,28 @@ fn test_get_name_from_tag() {
     let t: Option<Vec<ec2_tag>> = Some(vec![tag]);
     assert_eq!(name(&t), "api".to_string());
 }
+#[test]
+fn test_preview() {
+    let lines = preview(&sample_instance());
+    assert_eq!(lines[0], "id            i-2342545");
+    assert!(!lines.iter().any(|l| l.starts_with("public_ip")));
+    assert_eq!(lines.last().unwrap(), "  env=production");
+}
+#[test]
+fn test_host_names() {
+    let instance = |id: &str, name: &str| Instance {
+        id: id.to_string(),
+        name: name.to_string(),
+        ..sample_instance()
+    };
+    let instances = vec![
+        instance("i-1", "api"),
+        instance("i-2", "api"),
+        instance("i-3", "batch server"),
+        instance("i-4", ""),
+    ];
+    assert_eq!(
+        host_names(&instances),
+        vec!["api-i-1", "api-i-2", "batch-server", "i-4"]
+    );
+}
@@ -0,0 +1,174 @@
+use crate::awsutils::{clients, GlobalOpt};
+use crate::error::Result;
+use crate::instance::{address, get_instances, host_names, Instance, SearchQueryOpt, FIELDS};
+use crate::utils::{tag_value, Record};
+use aws_sdk_ec2::Client;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum InventoryOpt {
+    #[structopt(about = "Ansible dynamic inventory. groups by tags, ASG, AZ and instance type")]
+    Ansible(AnsibleOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AnsibleOpt {
+    #[structopt(
+        long,
+        conflicts_with = "host",
+        help = "print all groups and hosts. the default"
+    )]
+    list: bool,
+    #[structopt(long, help = "print variables of the host")]
+    host: Option<String>,
+    #[structopt(
+        short = "a",
+        long,
+        default_value = "private_ip",
+        possible_values = &["private_ip", "public_ip", "private_dns", "public_dns"],
+        help = "the instance field set to ansible_host"
+    )]
+    address: String,
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+}
+
+pub async fn matcher(global_opt: GlobalOpt, opt: InventoryOpt) -> Result<()> {
+    let clis = clients::<Client>(&global_opt).await?;
+    match opt {
+        InventoryOpt::Ansible(opt) => {
+            let instances = get_instances(&clis, &opt.query).await?;
+            let hosts: Vec<(String, &Instance)> = host_names(&instances)
+                .into_iter()
+                .zip(instances.iter())
+                .collect();
+            let inventory = match &opt.host {
+                Some(name) if !opt.list => hosts
+                    .iter()
+                    .find(|(h, _)| h == name)
+                    .map(|(_, i)| hostvars(*i, &opt.address))
+                    .unwrap_or_else(|| json!({})),
+                _ => ansible_list(&hosts, &opt.address),
+            };
+            println!("{}", serde_json::to_string_pretty(&inventory).unwrap());
+            Ok(())
+        }
+    }
+}
+
+// the output of `--list` with all host variables in _meta, so that ansible doesn't call `--host` for each host
+fn ansible_list(hosts: &[(String, &Instance)], address_field: &str) -> Value {
+    let mut groups: Map<String, Value> = Map::new();
+    let mut vars = Map::new();
+    for (name, i) in hosts {
+        // without the address, ansible can't connect to the host
+        if address(i, address_field).is_err() {
+            continue;
+        }
+        for g in groups_of(*i) {
+            let group = groups.entry(g).or_insert_with(|| json!({ "hosts": [] }));
+            group["hosts"].as_array_mut().unwrap().push(json!(name));
+        }
+        vars.insert(name.clone(), hostvars(*i, address_field));
+    }
+    groups.insert("_meta".to_string(), json!({ "hostvars": vars }));
+    Value::Object(groups)
+}
+
+fn groups_of<T: Record>(i: &T) -> Vec<String> {
+    let mut groups = vec![];
+    for t in i.tags() {
+        if t.key.starts_with("aws:") {
+            continue;
+        }
+        let value = t.value.clone().unwrap_or_default();
+        groups.push(group_name(&["tag", &t.key, &value]));
+    }
+    let asg = tag_value(i.tags(), "aws:autoscaling:groupName");
+    if !asg.is_empty() {
+        groups.push(group_name(&["asg", &asg]));
+    }
+    for (prefix, field) in [("az", "az"), ("type", "instance_type")] {
+        let v = i.attribute(field).unwrap_or_default();
+        if !v.is_empty() {
+            groups.push(group_name(&[prefix, &v]));
+        }
+    }
+    groups
+}
+
+// ansible group names are made of letters, digits and underscores
+fn group_name(parts: &[&str]) -> String {
+    parts
+        .join("_")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+#[test]
+fn test_group_name() {
+    assert_eq!(group_name(&["tag", "env", "prod"]), "tag_env_prod");
+    assert_eq!(group_name(&["type", "t3.micro"]), "type_t3_micro");
+    assert_eq!(group_name(&["asg", "api-web"]), "asg_api_web");
+}
+
+fn hostvars<T: Record>(i: &T, address_field: &str) -> Value {
+    let mut vars = Map::new();
+    vars.insert(
+        "ansible_host".to_string(),
+        json!(i.attribute(address_field).unwrap_or_default()),
+    );
+    for f in FIELDS {
+        let v = i.attribute(f).unwrap_or_default();
+        if !v.is_empty() {
+            vars.insert(format!("ec2_{}", f), json!(v));
+        }
+    }
+    let tags: Map<String, Value> = i
+        .tags()
+        .iter()
+        .map(|t| (t.key.clone(), json!(t.value.clone().unwrap_or_default())))
+        .collect();
+    vars.insert("ec2_tags".to_string(), Value::Object(tags));
+    Value::Object(vars)
+}
+#[test]
+fn test_groups_and_hostvars() {
+    use crate::utils::Tag;
+    struct R(Vec<Tag>);
+    impl Record for R {
+        fn attribute(&self, key: &str) -> Option<String> {
+            match key {
+                "private_ip" => Some("10.0.0.1".to_string()),
+                "az" => Some("us-east-1a".to_string()),
+                "instance_type" => Some("t3.micro".to_string()),
+                _ => None,
+            }
+        }
+        fn tags(&self) -> &[Tag] {
+            &self.0
+        }
+    }
+    let tag = |k: &str, v: &str| Tag {
+        key: k.to_string(),
+        value: Some(v.to_string()),
+    };
+    let r = R(vec![
+        tag("env", "prod"),
+        tag("aws:autoscaling:groupName", "api-web"),
+    ]);
+    assert_eq!(
+        groups_of(&r),
+        vec![
+            "tag_env_prod",
+            "asg_api_web",
+            "az_us_east_1a",
+            "type_t3_micro"
+        ]
+    );
+    let vars = hostvars(&r, "private_ip");
+    assert_eq!(vars["ansible_host"], "10.0.0.1");
+    assert_eq!(vars["ec2_az"], "us-east-1a");
+    assert_eq!(vars["ec2_tags"]["env"], "prod");
+}
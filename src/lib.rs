@@ -1,5 +1,15 @@
 pub mod autoscaling;
 pub mod awsutils;
+pub mod cache;
+pub mod config;
+pub mod error;
+pub mod filter;
 pub mod instance;
+pub mod inventory;
+pub mod output;
+pub mod picker;
+pub mod query;
+pub mod remote;
 pub mod targetgroup;
 pub mod utils;
+pub mod watch;
@@ -1,9 +1,18 @@
 use ec2_search::autoscaling;
 use ec2_search::awsutils::GlobalOpt;
+use ec2_search::cache::{cached_names, CacheOpt};
+use ec2_search::config::{self, ResourceConfig};
+use ec2_search::error::Error;
 use ec2_search::instance;
+use ec2_search::inventory;
+use ec2_search::output::{OutputFormat, OutputOpt, SortBy};
 use ec2_search::targetgroup;
-use std::io;
-use structopt::clap::Shell;
+use http::Uri;
+use regex::Regex;
+use std::env;
+use std::process;
+use std::time::Duration;
+use structopt::clap::{ErrorKind, Shell};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -17,6 +26,100 @@ struct Cli {
         help = "The region to use. Overrides config/env settings."
     )]
     region: Option<String>,
+
+    #[structopt(
+        global = true,
+        long,
+        use_delimiter = true,
+        conflicts_with = "region",
+        help = "Comma separated regions to search concurrently."
+    )]
+    regions: Vec<String>,
+
+    #[structopt(
+        global = true,
+        long = "all-regions",
+        conflicts_with_all = &["region", "regions"],
+        help = "Search all enabled regions concurrently."
+    )]
+    all_regions: bool,
+
+    #[structopt(
+        global = true,
+        long = "profile",
+        number_of_values = 1,
+        help = "The named profile to use. Repeat to search several accounts concurrently."
+    )]
+    profiles: Vec<String>,
+
+    #[structopt(
+        global = true,
+        long = "assume-role",
+        value_name = "arn",
+        number_of_values = 1,
+        help = "The role to assume with the credentials of each profile. Repeat to search several accounts concurrently."
+    )]
+    assume_roles: Vec<String>,
+
+    #[structopt(
+        global = true,
+        long = "endpoint-url",
+        env = "AWS_ENDPOINT_URL",
+        help = "Send requests to this endpoint instead of the default one. e.g. a local emulator. \
+    `endpoint_url` of the profile in ~/.aws/config is used if not given."
+    )]
+    endpoint_url: Option<Uri>,
+
+    #[structopt(
+        global = true,
+        long,
+        help = "Output format. One of:
+    table|json|ndjson|csv|tsv [default: table]"
+    )]
+    output: Option<OutputFormat>,
+
+    #[structopt(
+        global = true,
+        long = "no-headers",
+        help = "Don't print headers and counts."
+    )]
+    no_headers: bool,
+
+    #[structopt(
+        global = true,
+        long = "sort-by",
+        value_name = "column[,desc]",
+        help = "Sort rows by the column or field. e.g. launch_time,desc"
+    )]
+    sort_by: Option<SortBy>,
+
+    #[structopt(
+        global = true,
+        long,
+        use_delimiter = true,
+        help = "Comma separated columns or fields to print. e.g. id,name,private_ip,tag:env"
+    )]
+    columns: Vec<String>,
+
+    #[structopt(
+        global = true,
+        long = "cache-ttl",
+        env = "EC2S_CACHE_TTL",
+        value_name = "seconds",
+        help = "Reuse instances, target groups and auto scaling groups fetched within this time. [default: 0, never reused]"
+    )]
+    cache_ttl: Option<u64>,
+
+    #[structopt(global = true, long, help = "Fetch again ignoring the cache.")]
+    refresh: bool,
+
+    #[structopt(
+        global = true,
+        long,
+        conflicts_with = "refresh",
+        help = "Answer from the cache only, however old it is. No request is sent."
+    )]
+    offline: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -27,6 +130,8 @@ enum Command {
     TargetGroup(targetgroup::TargetGroupOpt),
     #[structopt(visible_alias = "asg", about = "Search auto scaling group")]
     AutoScalingGroup(autoscaling::AutoScalingGroupOpt),
+    #[structopt(about = "Prints instances as inventory of other tools")]
+    Inventory(inventory::InventoryOpt),
     #[structopt(about = "Prints version information")]
     Version,
     #[structopt(about = "Prints Completion")]
@@ -41,27 +146,113 @@ enum CompletionOpt {
 
 #[tokio::main]
 async fn main() {
-    let opt = Cli::from_args();
-    let global_opt = GlobalOpt { region: opt.region };
-    match opt.cmd {
+    // called by the completion scripts with the words of the command line.
+    // not a subcommand, because clap can't generate completions for a name with `__`
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("__complete") {
+        complete(&args[2..]);
+        return;
+    }
+    let config = config::load().unwrap_or_else(|err| exit(err));
+    let args = config
+        .expand_queries(args, pending_value)
+        .unwrap_or_else(|err| exit(err));
+    let opt = Cli::from_iter(args);
+    let format = match (opt.output, &config.output) {
+        (Some(f), _) => f,
+        (None, Some(f)) => f.parse().unwrap_or_else(|err: String| exit(err.into())),
+        (None, None) => OutputFormat::Table,
+    };
+    // the config file is a default, so the environment variables of the sdk win over it
+    let env_set = |k: &str| env::var_os(k).is_some_and(|v| !v.is_empty());
+    let profiles = match (opt.profiles.is_empty(), config.profile) {
+        (true, Some(p)) if !env_set("AWS_PROFILE") => vec![p],
+        _ => opt.profiles,
+    };
+    let region = match opt.region {
+        None if env_set("AWS_REGION") || env_set("AWS_DEFAULT_REGION") => None,
+        r => r.or(config.region),
+    };
+    let resource = match &opt.cmd {
+        Command::Instance(_) | Command::Inventory(_) => &config.instance,
+        Command::TargetGroup(_) => &config.target_group,
+        Command::AutoScalingGroup(_) => &config.auto_scaling_group,
+        _ => &ResourceConfig::default(),
+    };
+    let global_opt = GlobalOpt {
+        region,
+        regions: opt.regions,
+        all_regions: opt.all_regions,
+        profiles,
+        assume_roles: opt.assume_roles,
+        endpoint_url: opt.endpoint_url,
+        output: OutputOpt {
+            format,
+            no_headers: opt.no_headers,
+            sort_by: opt.sort_by,
+            columns: opt.columns,
+            watch: None,
+        },
+        cache: CacheOpt {
+            ttl: Duration::from_secs(opt.cache_ttl.or(config.cache_ttl).unwrap_or(0)),
+            refresh: opt.refresh,
+            offline: opt.offline,
+            identity: String::new(),
+        },
+        tag_columns: resource.tag_columns(),
+    };
+    let result = match opt.cmd {
         Command::Instance(opt) => instance::matcher(global_opt, opt).await,
         Command::TargetGroup(opt) => targetgroup::matcher(global_opt, opt).await,
         Command::AutoScalingGroup(opt) => autoscaling::matcher(global_opt, opt).await,
-        Command::Version => version(),
-        Command::Completion(opt) => match opt {
-            CompletionOpt::Bash => completion(Shell::Bash),
-            CompletionOpt::Zsh => completion(Shell::Zsh),
-            CompletionOpt::Fish => completion(Shell::Fish),
-        },
+        Command::Inventory(opt) => inventory::matcher(global_opt, opt).await,
+        Command::Version => {
+            version();
+            Ok(())
+        }
+        Command::Completion(opt) => {
+            match opt {
+                CompletionOpt::Bash => completion(Shell::Bash),
+                CompletionOpt::Zsh => completion(Shell::Zsh),
+                CompletionOpt::Fish => completion(Shell::Fish),
+            }
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        exit(err);
+    }
+}
+
+// the arguments end with an option which takes a value, such as `-q`
+fn pending_value(args: &[String]) -> bool {
+    match Cli::clap().get_matches_from_safe(args) {
+        Err(e) => e.kind == ErrorKind::EmptyValue,
+        Ok(_) => false,
     }
 }
+#[test]
+fn test_pending_value() {
+    let args = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+    assert!(pending_value(&args("ec2s i info -q")));
+    assert!(pending_value(&args("ec2s i info -e --region")));
+    assert!(!pending_value(&args("ec2s i info -e")));
+    assert!(!pending_value(&args("ec2s i info")));
+}
+
+fn exit(err: Error) -> ! {
+    eprintln!("Error: {}", err);
+    process::exit(err.exit_code())
+}
 
 fn version() {
     println!("ec2-search {}", env!("CARGO_PKG_VERSION"))
 }
 
 fn completion(s: Shell) {
-    Cli::clap().gen_completions_to(env!("CARGO_BIN_NAME"), s, &mut io::stdout())
+    let mut script = vec![];
+    Cli::clap().gen_completions_to(env!("CARGO_BIN_NAME"), s, &mut script);
+    print!("{}", complete_query(s, &String::from_utf8_lossy(&script)));
 }
 #[test]
 fn test_completion() {
@@ -69,3 +260,66 @@ fn test_completion() {
     completion(Shell::Zsh);
     completion(Shell::Fish);
 }
+
+// clap only completes files for option values, so -q is replaced to complete cached names
+fn complete_query(s: Shell, script: &str) -> String {
+    let (pattern, replace) = match s {
+        Shell::Bash => (
+            r#"(?m)^(\s*(?:-q|--query)\)\n\s*)COMPREPLY=\(\$\(compgen -f "\$\{cur\}"\)\)"#,
+            r#"${1}COMPREPLY=($$(compgen -W "$$(ec2s __complete "$${COMP_WORDS[@]:0:COMP_CWORD}" 2>/dev/null)" -- "$${cur}"))"#,
+        ),
+        Shell::Zsh => (
+            r"(?m)^('(?:-q\+|--query=)\[[^\]]*\])' \\$",
+            r#"${1}:query:{compadd -- $${(f)"$$(ec2s __complete $${(z)LBUFFER} 2>/dev/null)"}}' \"#,
+        ),
+        Shell::Fish => (
+            r"(?m)^(complete -c ec2s .* -s q -l query .*)$",
+            r#"${1} -x -a "(ec2s __complete (commandline -opc))""#,
+        ),
+        _ => return script.to_string(),
+    };
+    Regex::new(pattern)
+        .unwrap()
+        .replace_all(script, replace)
+        .to_string()
+}
+#[test]
+fn test_complete_query() {
+    for s in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+        let mut script = vec![];
+        Cli::clap().gen_completions_to("ec2s", s, &mut script);
+        let script = complete_query(s, &String::from_utf8_lossy(&script));
+        assert!(script.contains("ec2s __complete"), "{}", s);
+    }
+}
+
+// print cached names of the resource of the subcommand in the words
+fn complete(words: &[String]) {
+    if let Some(resource) = complete_resource(words) {
+        for name in cached_names(resource) {
+            println!("{}", name);
+        }
+    }
+}
+
+fn complete_resource(words: &[String]) -> Option<&'static str> {
+    words.iter().find_map(|w| match w.as_str() {
+        "instance" | "i" | "inventory" => Some("instances"),
+        "target-group" | "tg" => Some("target_groups"),
+        "auto-scaling-group" | "asg" => Some("auto_scaling_groups"),
+        _ => None,
+    })
+}
+#[test]
+fn test_complete_resource() {
+    let words = |s: &str| -> Vec<String> { s.split(' ').map(String::from).collect() };
+    assert_eq!(
+        complete_resource(&words("ec2s --region x tg health -q")),
+        Some("target_groups")
+    );
+    assert_eq!(
+        complete_resource(&words("ec2s i ssh -q")),
+        Some("instances")
+    );
+    assert_eq!(complete_resource(&words("ec2s version")), None);
+}
@@ -0,0 +1,541 @@
+use crate::awsutils::datetime_rfc3339;
+use crate::error;
+use crate::utils::{print_table, print_table_highlighted, tag_key, Record};
+use aws_smithy_types::DateTime;
+use itertools::Itertools;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::env;
+use std::io::{self, stdout, Write};
+use std::process;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Ndjson,
+    Csv,
+    Tsv,
+}
+
+#[derive(Clone)]
+pub struct OutputOpt {
+    pub format: OutputFormat,
+    pub no_headers: bool,
+    pub sort_by: Option<SortBy>,
+    // columns to print, in this order. all columns if empty
+    pub columns: Vec<String>,
+    // tables are redrawn in place with --watch
+    pub watch: Option<Watching>,
+}
+
+// the rows drawn last time, to highlight changes
+#[derive(Clone, Default)]
+pub struct Watching {
+    previous: RefCell<Option<Vec<Vec<String>>>>,
+}
+
+impl Watching {
+    fn redraw(
+        &self,
+        out: &mut impl Write,
+        header: Vec<String>,
+        rows: Vec<Vec<String>>,
+        no_headers: bool,
+    ) -> io::Result<()> {
+        let previous = self.previous.replace(Some(rows.clone()));
+        let (changed, gone) = changes(previous.as_deref(), &rows);
+        let len = rows.len();
+        // clear the screen and move to the top left
+        write!(out, "\x1b[H\x1b[2J")?;
+        if !no_headers {
+            let command: Vec<String> = env::args().collect();
+            let now = datetime_rfc3339(DateTime::from(SystemTime::now()));
+            writeln!(out, "{}  {}\n", command.join(" "), now)?;
+        }
+        out.flush()?;
+        print_table_highlighted(header, rows, &changed)?;
+        if !no_headers {
+            let count = changed.iter().filter(|c| **c).count();
+            writeln!(out, "counts: {}  changed: {}  gone: {}", len, count, gone)?;
+        }
+        Ok(())
+    }
+}
+
+// rows which are new or different from the last time, and the number of rows gone since then
+fn changes(previous: Option<&[Vec<String>]>, rows: &[Vec<String>]) -> (Vec<bool>, usize) {
+    let previous = match previous {
+        Some(p) => p,
+        None => return (vec![false; rows.len()], 0),
+    };
+    let changed = rows.iter().map(|r| !previous.contains(r)).collect();
+    let gone = previous.iter().filter(|p| !rows.contains(p)).count();
+    (changed, gone)
+}
+#[test]
+fn test_changes() {
+    let rows = |rs: &[&[&str]]| -> Vec<Vec<String>> {
+        rs.iter()
+            .map(|r| r.iter().map(|c| c.to_string()).collect())
+            .collect()
+    };
+    let before = rows(&[
+        &["i-1", "healthy"],
+        &["i-2", "initial"],
+        &["i-3", "healthy"],
+    ]);
+    let after = rows(&[
+        &["i-1", "healthy"],
+        &["i-2", "healthy"],
+        &["i-4", "initial"],
+    ]);
+    assert_eq!(changes(None, &after), (vec![false, false, false], 0));
+    assert_eq!(changes(Some(&before), &after), (vec![false, true, true], 2));
+}
+
+// `<column>[,desc]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortBy {
+    pub column: String,
+    pub desc: bool,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, order) = s.split_once(',').unwrap_or((s, "asc"));
+        let desc = match order {
+            "asc" => false,
+            "desc" => true,
+            o => return Err(format!("sort order must be asc or desc, not '{}'", o)),
+        };
+        Ok(SortBy {
+            column: column.to_string(),
+            desc,
+        })
+    }
+}
+#[test]
+fn test_sort_by_from_str() {
+    let s = |c: &str, desc| SortBy {
+        column: c.to_string(),
+        desc,
+    };
+    assert_eq!("az".parse(), Ok(s("az", false)));
+    assert_eq!("launch_time,desc".parse(), Ok(s("launch_time", true)));
+    assert!("az,up".parse::<SortBy>().is_err());
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            a => Err(format!(
+                "unable to match a printer suitable for the output format '{}'. \
+                 allow formats are: table,json,ndjson,csv,tsv",
+                a
+            )),
+        }
+    }
+}
+#[test]
+fn test_format_from_str() {
+    assert_eq!("table".parse::<OutputFormat>(), Ok(OutputFormat::Table));
+    assert_eq!("json".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+    assert_eq!("ndjson".parse::<OutputFormat>(), Ok(OutputFormat::Ndjson));
+    assert_eq!("csv".parse::<OutputFormat>(), Ok(OutputFormat::Csv));
+    assert_eq!("tsv".parse::<OutputFormat>(), Ok(OutputFormat::Tsv));
+    assert!("yaml".parse::<OutputFormat>().is_err());
+}
+
+// print records as table rows or serialized records, depending on format.
+// `rows` has a row for each record, in the same order
+pub fn print<T: Serialize + Record>(
+    opt: &OutputOpt,
+    records: &[T],
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+) -> error::Result<()> {
+    print_rows(opt, records, header, rows, true)
+}
+
+// the table without the `counts:` line, e.g. names to be piped to other commands
+pub fn print_without_counts<T: Serialize + Record>(
+    opt: &OutputOpt,
+    records: &[T],
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+) -> error::Result<()> {
+    print_rows(opt, records, header, rows, false)
+}
+
+fn print_rows<T: Serialize + Record>(
+    opt: &OutputOpt,
+    records: &[T],
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+    counts: bool,
+) -> error::Result<()> {
+    debug_assert_eq!(records.len(), rows.len());
+    let (header, rows, order) = arrange(opt, records, header, rows)?;
+    let records: Vec<&T> = order.iter().map(|i| &records[*i]).collect();
+    let header = if opt.no_headers { vec![] } else { header };
+    let out = &mut stdout().lock();
+    let result = match opt.format {
+        OutputFormat::Table if opt.watch.is_some() => {
+            opt.watch
+                .as_ref()
+                .unwrap()
+                .redraw(out, header, rows, opt.no_headers)
+        }
+        OutputFormat::Table => {
+            let len = rows.len();
+            print_table(header, rows).and_then(|_| match counts && !opt.no_headers {
+                true => writeln!(out, "counts: {}", len),
+                false => Ok(()),
+            })
+        }
+        OutputFormat::Json => writeln!(out, "{}", to_json(&records)),
+        OutputFormat::Ndjson => records
+            .iter()
+            .try_for_each(|r| writeln!(out, "{}", to_ndjson(r))),
+        OutputFormat::Csv => print_delimited(out, &header, &rows, ',', csv_field),
+        OutputFormat::Tsv => print_delimited(out, &header, &rows, '\t', tsv_field),
+    };
+    written(result.and_then(|_| out.flush()))
+}
+
+// a closed pipe (e.g. `| head`) ends the command quietly as other unix tools do
+fn written(result: io::Result<()>) -> error::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+        r => Ok(r?),
+    }
+}
+
+// the header, rows and the original index of each row
+type Arranged = (Vec<String>, Vec<Vec<String>>, Vec<usize>);
+
+// sort rows and select columns. each row is of the record at the same index
+fn arrange<T: Record>(
+    opt: &OutputOpt,
+    records: &[T],
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+) -> Result<Arranged, String> {
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    if let Some(s) = &opt.sort_by {
+        let values = column_values(&s.column, records, &header, &rows)
+            .ok_or_else(|| format!("unknown column '{}' to sort by", s.column))?;
+        order.sort_by(|a, b| {
+            let o = compare(&values[*a], &values[*b]);
+            if s.desc {
+                o.reverse()
+            } else {
+                o
+            }
+        });
+    }
+    if opt.columns.is_empty() {
+        let rows = order.iter().map(|i| rows[*i].clone()).collect();
+        return Ok((header, rows, order));
+    }
+    let columns = opt
+        .columns
+        .iter()
+        .map(|c| {
+            column_values(c, records, &header, &rows)
+                .ok_or_else(|| format!("unknown column '{}'", c))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let rows = order
+        .iter()
+        .map(|i| columns.iter().map(|c| c[*i].clone()).collect())
+        .collect();
+    let header = opt
+        .columns
+        .iter()
+        .map(|c| match header_index(c, &header) {
+            Some(i) => header[i].clone(),
+            None => c.clone(),
+        })
+        .collect();
+    Ok((header, rows, order))
+}
+
+// a column of the table, or a field of the records when the table doesn't have it
+fn column_values<T: Record>(
+    column: &str,
+    records: &[T],
+    header: &[String],
+    rows: &[Vec<String>],
+) -> Option<Vec<String>> {
+    if let Some(i) = header_index(column, header) {
+        return Some(
+            rows.iter()
+                .map(|r| r.get(i).cloned().unwrap_or_default())
+                .collect(),
+        );
+    }
+    records.iter().map(|r| r.field(column)).collect()
+}
+
+// `private_ip` matches `PrivateIP` and `Private IP`. `tag:env` matches the tag column `env`
+fn header_index(column: &str, header: &[String]) -> Option<usize> {
+    if let Some(key) = tag_key(column) {
+        return header.iter().position(|h| h == key);
+    }
+    let normalize = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    };
+    header
+        .iter()
+        .position(|h| normalize(h) == normalize(column))
+}
+
+// numbers such as ports and capacities are compared as numbers
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+#[test]
+fn test_arrange() {
+    struct R(&'static str);
+    impl Record for R {
+        fn attribute(&self, key: &str) -> Option<String> {
+            match key {
+                "launch_time" => Some(self.0.to_string()),
+                _ => None,
+            }
+        }
+        fn tags(&self) -> &[crate::utils::Tag] {
+            &[]
+        }
+    }
+    let opt = |sort_by: Option<&str>, columns: &[&str]| OutputOpt {
+        format: OutputFormat::Table,
+        no_headers: false,
+        sort_by: sort_by.map(|s| s.parse().unwrap()),
+        columns: columns.iter().map(|c| c.to_string()).collect(),
+        watch: None,
+    };
+    let records = [R("2022-03"), R("2022-01"), R("2022-02")];
+    let header = || -> Vec<String> {
+        vec![
+            "Private IP".to_string(),
+            "Port".to_string(),
+            "env".to_string(),
+        ]
+    };
+    let rows = || -> Vec<Vec<String>> {
+        [
+            ["10.0.0.1", "8080", "prod"],
+            ["10.0.0.2", "80", "stg"],
+            ["10.0.0.3", "443", "dev"],
+        ]
+        .iter()
+        .map(|r| r.iter().map(|c| c.to_string()).collect())
+        .collect()
+    };
+    let (_, _, order) = arrange(&opt(Some("port"), &[]), &records, header(), rows()).unwrap();
+    assert_eq!(order, vec![1, 2, 0]);
+    let (_, _, order) = arrange(
+        &opt(Some("launch_time,desc"), &[]),
+        &records,
+        header(),
+        rows(),
+    )
+    .unwrap();
+    assert_eq!(order, vec![0, 2, 1]);
+    let (h, r, _) = arrange(
+        &opt(Some("tag:env"), &["tag:env", "private_ip", "launch_time"]),
+        &records,
+        header(),
+        rows(),
+    )
+    .unwrap();
+    assert_eq!(h, vec!["env", "Private IP", "launch_time"]);
+    assert_eq!(r[0], vec!["dev", "10.0.0.3", "2022-02"]);
+    assert!(arrange(&opt(Some("unknown"), &[]), &records, header(), rows()).is_err());
+    assert!(arrange(&opt(None, &["unknown"]), &records, header(), rows()).is_err());
+}
+
+fn print_delimited(
+    out: &mut impl Write,
+    header: &[String],
+    rows: &[Vec<String>],
+    delimiter: char,
+    field: fn(&str) -> String,
+) -> io::Result<()> {
+    let line = |r: &[String]| r.iter().map(|c| field(c)).join(&delimiter.to_string());
+    if !header.is_empty() {
+        writeln!(out, "{}", line(header))?;
+    }
+    rows.iter().try_for_each(|r| writeln!(out, "{}", line(r)))
+}
+
+// quote a field only when it contains a delimiter, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// tsv has no quoting, so tabs and line breaks are replaced by a space
+fn tsv_field(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+#[test]
+fn test_delimited_field() {
+    assert_eq!(csv_field("api"), "api");
+    assert_eq!(csv_field("[\"a\", \"b\"]"), "\"[\"\"a\"\", \"\"b\"\"]\"");
+    assert_eq!(tsv_field("a\tb\nc"), "a b c");
+}
+
+// render `{{field}}` placeholders with the fields of a record
+pub fn render_template<T: Record>(template: &str, record: &T) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder
+            .find("}}")
+            .ok_or_else(|| format!("unclosed placeholder in template '{}'", template))?;
+        let key = placeholder[..end].trim();
+        let value = record
+            .field(key)
+            .ok_or_else(|| format!("unknown field '{}' in template", key))?;
+        result.push_str(&value);
+        rest = &placeholder[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// parse kubectl style custom columns, e.g. `ID:id,IP:private_ip`
+pub fn parse_custom_columns(spec: &str) -> Result<Vec<(String, String)>, String> {
+    spec.split(',')
+        .map(|c| match c.split_once(':') {
+            Some((header, key)) if !header.is_empty() && !key.is_empty() => {
+                Ok((header.to_string(), key.to_string()))
+            }
+            _ => Err(format!(
+                "custom column '{}' must be written as <header>:<field>",
+                c
+            )),
+        })
+        .collect()
+}
+
+// build a row per record from custom columns
+pub fn custom_columns_rows<T: Record>(
+    columns: &[(String, String)],
+    records: &[T],
+) -> Result<Vec<Vec<String>>, String> {
+    records
+        .iter()
+        .map(|r| {
+            columns
+                .iter()
+                .map(|(_, key)| {
+                    r.field(key)
+                        .ok_or_else(|| format!("unknown field '{}' in custom columns", key))
+                })
+                .collect()
+        })
+        .collect()
+}
+#[test]
+fn test_template_and_custom_columns() {
+    struct R(Vec<crate::utils::Tag>);
+    impl Record for R {
+        fn attribute(&self, key: &str) -> Option<String> {
+            match key {
+                "id" => Some("i-1".to_string()),
+                _ => None,
+            }
+        }
+        fn tags(&self) -> &[crate::utils::Tag] {
+            &self.0
+        }
+    }
+    let r = R(vec![crate::utils::Tag {
+        key: "env".to_string(),
+        value: Some("prod".to_string()),
+    }]);
+    assert_eq!(
+        render_template("{{id}} {{ tag.env }}!", &r),
+        Ok("i-1 prod!".to_string())
+    );
+    assert!(render_template("{{unknown}}", &r).is_err());
+    assert!(render_template("{{id", &r).is_err());
+
+    let columns = parse_custom_columns("ID:id,Env:tag.env").unwrap();
+    assert_eq!(
+        columns,
+        vec![
+            ("ID".to_string(), "id".to_string()),
+            ("Env".to_string(), "tag.env".to_string())
+        ]
+    );
+    assert_eq!(
+        custom_columns_rows(&columns, &[r]),
+        Ok(vec![vec!["i-1".to_string(), "prod".to_string()]])
+    );
+    assert!(parse_custom_columns("ID").is_err());
+    assert!(custom_columns_rows(&parse_custom_columns("X:x").unwrap(), &[R(vec![])]).is_err());
+}
+
+fn to_json<T: Serialize>(records: &[T]) -> String {
+    match serde_json::to_string_pretty(records) {
+        Ok(r) => r,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn to_ndjson<T: Serialize>(record: &T) -> String {
+    match serde_json::to_string(record) {
+        Ok(r) => r,
+        Err(err) => panic!("{}", err),
+    }
+}
+#[test]
+fn test_to_json() {
+    #[derive(Serialize)]
+    struct R {
+        id: String,
+        tags: Vec<crate::utils::Tag>,
+    }
+    let records = vec![R {
+        id: "i-1".to_string(),
+        tags: vec![crate::utils::Tag {
+            key: "env".to_string(),
+            value: None,
+        }],
+    }];
+    assert_eq!(
+        to_ndjson(&records[0]),
+        r#"{"id":"i-1","tags":[{"key":"env","value":null}]}"#
+    );
+    assert_eq!(
+        to_json(&records),
+        "[\n  {\n    \"id\": \"i-1\",\n    \"tags\": [\n      {\n        \"key\": \"env\",\n        \"value\": null\n      }\n    ]\n  }\n]"
+    );
+}
@@ -0,0 +1,267 @@
+use crate::error::{single, Error, Result};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, size, Clear, ClearType, EnterAlternateScreen,
+    LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use std::cmp::Reverse;
+use std::io::{stderr, stdin, IsTerminal, Stderr, Write};
+
+// the picker is drawn on stderr so that stdout can be piped
+pub fn is_interactive() -> bool {
+    stdin().is_terminal() && stderr().is_terminal()
+}
+
+// let the user choose one record when several match. falls back to `single` without a terminal
+pub fn choose<'a, T>(records: &'a [T], name: &str, label: impl Fn(&T) -> String) -> Result<&'a T> {
+    choose_records(records, name, label, None)
+}
+
+// same as `choose` with a preview pane of the record under the cursor
+pub fn choose_with_preview<'a, T>(
+    records: &'a [T],
+    name: &str,
+    label: impl Fn(&T) -> String,
+    preview: impl Fn(&T) -> Vec<String>,
+) -> Result<&'a T> {
+    choose_records(records, name, label, Some(&preview))
+}
+
+// lines of the preview pane for a record
+type Preview<'p, T> = &'p dyn Fn(&T) -> Vec<String>;
+
+fn choose_records<'a, T>(
+    records: &'a [T],
+    name: &str,
+    label: impl Fn(&T) -> String,
+    preview: Option<Preview<T>>,
+) -> Result<&'a T> {
+    if records.len() > 1 && is_interactive() {
+        let items: Vec<String> = records.iter().map(label).collect();
+        let prompt = format!("{} {} matched", records.len(), name);
+        let chosen = match preview {
+            Some(p) => pick_with_preview(&prompt, &items, |i| p(&records[i]))?,
+            None => pick(&prompt, &items)?,
+        };
+        return match chosen {
+            Some(i) => Ok(&records[i]),
+            None => Err(Error::Cancelled),
+        };
+    }
+    single(records, name)
+}
+
+// indices of items matching the query, best first
+pub fn fuzzy_filter(items: &[String], query: &str) -> Vec<usize> {
+    if query.is_empty() {
+        return (0..items.len()).collect();
+    }
+    let matcher = SkimMatcherV2::default();
+    let mut scored: Vec<(i64, usize)> = items
+        .iter()
+        .enumerate()
+        .filter_map(|(i, item)| matcher.fuzzy_match(item, query).map(|s| (s, i)))
+        .collect();
+    // stable sort keeps the original order for the same score
+    scored.sort_by_key(|(score, _)| Reverse(*score));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+#[test]
+fn test_fuzzy_filter() {
+    let items: Vec<String> = vec!["api-web", "batch", "api-worker"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(fuzzy_filter(&items, ""), vec![0, 1, 2]);
+    assert_eq!(fuzzy_filter(&items, "awk"), vec![2]);
+    assert_eq!(fuzzy_filter(&items, "api").len(), 2);
+    assert!(fuzzy_filter(&items, "xyz").is_empty());
+}
+
+// incremental fuzzy search over items. returns None when cancelled
+pub fn pick(prompt: &str, items: &[String]) -> Result<Option<usize>> {
+    run(prompt, items, None)
+}
+
+// same as `pick` with a preview pane of the item under the cursor
+pub fn pick_with_preview(
+    prompt: &str,
+    items: &[String],
+    preview: impl Fn(usize) -> Vec<String>,
+) -> Result<Option<usize>> {
+    run(prompt, items, Some(&preview))
+}
+
+fn run(
+    prompt: &str,
+    items: &[String],
+    preview: Option<&dyn Fn(usize) -> Vec<String>>,
+) -> Result<Option<usize>> {
+    let mut screen = Screen::new()?;
+    let mut state = State::default();
+    loop {
+        let matched = fuzzy_filter(items, &state.query);
+        state.cursor = state.cursor.min(matched.len().saturating_sub(1));
+        let lines = match (preview, matched.get(state.cursor)) {
+            (Some(p), Some(i)) => p(*i),
+            _ => vec![],
+        };
+        screen.draw(prompt, items, &matched, &state, preview.map(|_| lines))?;
+        if let Event::Key(key) = read()? {
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            match state.update(key, matched.len()) {
+                Action::Continue => {}
+                Action::Cancel => return Ok(None),
+                Action::Select => return Ok(matched.get(state.cursor).copied()),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    query: String,
+    cursor: usize,
+}
+
+enum Action {
+    Continue,
+    Cancel,
+    Select,
+}
+
+impl State {
+    fn update(&mut self, key: KeyEvent, len: usize) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Esc => return Action::Cancel,
+            KeyCode::Char('c') if ctrl => return Action::Cancel,
+            KeyCode::Enter if len > 0 => return Action::Select,
+            KeyCode::Up => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Char('p') if ctrl => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Down if self.cursor + 1 < len => self.cursor += 1,
+            KeyCode::Char('n') if ctrl && self.cursor + 1 < len => self.cursor += 1,
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.cursor = 0;
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.query.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.cursor = 0;
+            }
+            _ => {}
+        }
+        Action::Continue
+    }
+}
+#[test]
+fn test_state_update() {
+    let key = |c| KeyEvent::new(c, KeyModifiers::NONE);
+    let mut s = State::default();
+    s.update(key(KeyCode::Char('a')), 3);
+    s.update(key(KeyCode::Down), 3);
+    s.update(key(KeyCode::Down), 3);
+    s.update(key(KeyCode::Down), 3);
+    assert_eq!((s.query.as_str(), s.cursor), ("a", 2));
+    s.update(key(KeyCode::Backspace), 3);
+    assert_eq!((s.query.as_str(), s.cursor), ("", 0));
+    assert!(matches!(s.update(key(KeyCode::Enter), 0), Action::Continue));
+    assert!(matches!(s.update(key(KeyCode::Enter), 1), Action::Select));
+    assert!(matches!(s.update(key(KeyCode::Esc), 1), Action::Cancel));
+}
+
+// raw mode on the alternate screen. restored on drop
+struct Screen {
+    out: Stderr,
+}
+
+impl Screen {
+    fn new() -> Result<Screen> {
+        let mut out = stderr();
+        enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide)?;
+        Ok(Screen { out })
+    }
+
+    fn draw(
+        &mut self,
+        prompt: &str,
+        items: &[String],
+        matched: &[usize],
+        state: &State,
+        preview: Option<Vec<String>>,
+    ) -> Result<()> {
+        let (total, height) = size()?;
+        let total = total as usize;
+        // the preview takes the right half
+        let width = match preview {
+            Some(_) => total / 2,
+            None => total,
+        };
+        let rows = (height as usize).saturating_sub(2).max(1);
+        // scroll so that the cursor is visible
+        let offset = (state.cursor + 1).saturating_sub(rows);
+        queue!(self.out, Clear(ClearType::All), MoveTo(0, 0))?;
+        queue!(
+            self.out,
+            Print(truncate(&format!("> {}", state.query), width))
+        )?;
+        queue!(
+            self.out,
+            MoveTo(0, 1),
+            SetAttribute(Attribute::Dim),
+            Print(truncate(
+                &format!("  {}/{} {}", matched.len(), items.len(), prompt),
+                width
+            )),
+            SetAttribute(Attribute::Reset)
+        )?;
+        for (row, i) in matched.iter().skip(offset).take(rows).enumerate() {
+            let line = truncate(&format!("  {}", items[*i]), width);
+            queue!(self.out, MoveTo(0, row as u16 + 2))?;
+            if offset + row == state.cursor {
+                queue!(
+                    self.out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(self.out, Print(line))?;
+            }
+        }
+        for (row, line) in preview.iter().flatten().take(height as usize).enumerate() {
+            let line = truncate(line, total.saturating_sub(width + 2));
+            queue!(
+                self.out,
+                MoveTo(width as u16, row as u16),
+                Print("│ "),
+                Print(line)
+            )?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = execute!(self.out, Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+fn truncate(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
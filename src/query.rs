@@ -0,0 +1,104 @@
+use crate::utils::name_query;
+use regex::Regex;
+
+// how a query is matched against names, ids and tags
+pub enum Matcher {
+    All,
+    // patterns with asterisk wildcards. built by `name_query`
+    Wildcard(Vec<String>),
+    Regex(Regex),
+}
+
+// values which a query is matched against
+pub trait Searchable {
+    fn search_keys(&self) -> Vec<&str>;
+}
+
+impl Matcher {
+    // comma separated query is searched OR, except regex which has its own alternation
+    pub fn new(query: Option<&str>, exact: bool, regex: bool) -> Result<Matcher, String> {
+        let query = match query {
+            None => return Ok(Matcher::All),
+            Some(q) => q.to_string(),
+        };
+        if regex {
+            return Regex::new(&query)
+                .map(Matcher::Regex)
+                .map_err(|e| format!("invalid regex '{}': {}", query, e));
+        }
+        let patterns = if exact {
+            name_query(&None, &Some(query))
+        } else {
+            name_query(&Some(query), &None)
+        };
+        Ok(Matcher::Wildcard(patterns.unwrap_or_default()))
+    }
+
+    pub fn is_match(&self, s: &str) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Wildcard(patterns) => patterns.iter().any(|p| wildcard_match(p, s)),
+            Matcher::Regex(r) => r.is_match(s),
+        }
+    }
+
+    pub fn matches<T: Searchable>(&self, t: &T) -> bool {
+        match self {
+            Matcher::All => true,
+            _ => t.search_keys().iter().any(|k| self.is_match(k)),
+        }
+    }
+}
+
+// match with `*` as a wildcard of any characters
+pub fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == s;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !s.starts_with(first) || s.len() < first.len() + last.len() || !s.ends_with(last) {
+        return false;
+    }
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+#[test]
+fn test_wildcard_match() {
+    assert!(wildcard_match("*api*", "test-api-1"));
+    assert!(wildcard_match("api", "api"));
+    assert!(!wildcard_match("api", "api-worker"));
+    assert!(wildcard_match("api-*", "api-worker"));
+    assert!(!wildcard_match("api-*", "test-api-worker"));
+    assert!(wildcard_match("a*b*c", "a-b-c"));
+    assert!(!wildcard_match("a*b*c", "a-c-b"));
+    assert!(!wildcard_match("ab*ba", "aba"));
+    assert!(wildcard_match("*", ""));
+}
+
+#[test]
+fn test_matcher() {
+    let m = Matcher::new(Some("api,test"), false, false).unwrap();
+    assert!(m.is_match("api-worker-canary"));
+    assert!(m.is_match("stg-test"));
+    assert!(!m.is_match("web"));
+
+    let m = Matcher::new(Some("api,test"), true, false).unwrap();
+    assert!(m.is_match("api"));
+    assert!(!m.is_match("api-worker-canary"));
+
+    let m = Matcher::new(Some("^api-(web|worker)$"), false, true).unwrap();
+    assert!(m.is_match("api-web"));
+    assert!(!m.is_match("api-worker-canary"));
+    assert!(Matcher::new(Some("("), false, true).is_err());
+
+    assert!(Matcher::new(None, true, false)
+        .unwrap()
+        .is_match("anything"));
+}
@@ -0,0 +1,323 @@
+use crate::error::{Error, Result};
+use serde_json::json;
+use std::process::{Command, Stdio};
+use structopt::StructOpt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::Semaphore;
+
+// how to connect to an instance with ssh
+#[derive(Debug, StructOpt)]
+pub struct SshOpt {
+    #[structopt(
+        short = "a",
+        long,
+        default_value = "private_ip",
+        possible_values = &["private_ip", "public_ip", "private_dns", "public_dns"],
+        help = "the instance field to connect to"
+    )]
+    pub address: String,
+    #[structopt(short = "l", long, help = "the user to log in as")]
+    pub user: Option<String>,
+    #[structopt(short = "i", long, help = "the private key file")]
+    pub identity: Option<String>,
+    #[structopt(
+        short = "J",
+        long,
+        help = "the bastion to connect through. passed to ssh as ProxyJump"
+    )]
+    pub jump: Option<String>,
+}
+
+// arguments of ssh to run the command (or a login shell if empty) on the host
+pub fn ssh_args(opt: &SshOpt, host: &str, command: &[String]) -> Vec<String> {
+    let mut args = vec![];
+    let options = [("-l", &opt.user), ("-i", &opt.identity), ("-J", &opt.jump)];
+    for (flag, value) in options {
+        if let Some(v) = value {
+            args.push(flag.to_string());
+            args.push(v.clone());
+        }
+    }
+    args.push(host.to_string());
+    args.extend(command.iter().cloned());
+    args
+}
+#[test]
+fn test_ssh_args() {
+    let mut opt = SshOpt {
+        address: "private_ip".to_string(),
+        user: None,
+        identity: None,
+        jump: None,
+    };
+    assert_eq!(ssh_args(&opt, "10.0.0.1", &[]), vec!["10.0.0.1"]);
+    opt.user = Some("ec2-user".to_string());
+    opt.jump = Some("bastion".to_string());
+    assert_eq!(
+        ssh_args(&opt, "10.0.0.1", &["uptime".to_string()]),
+        vec!["-l", "ec2-user", "-J", "bastion", "10.0.0.1", "uptime"]
+    );
+}
+
+// ssh_args for running on several hosts at once. ssh fails instead of prompting for
+// a password or a host key on the shared terminal, and gives up on unreachable hosts
+pub fn batch_ssh_args(opt: &SshOpt, host: &str, command: &[String]) -> Vec<String> {
+    ["-o", "BatchMode=yes", "-o", "ConnectTimeout=10"]
+        .iter()
+        .map(|a| a.to_string())
+        .chain(ssh_args(opt, host, command))
+        .collect()
+}
+#[test]
+fn test_batch_ssh_args() {
+    let opt = SshOpt {
+        address: "private_ip".to_string(),
+        user: None,
+        identity: None,
+        jump: None,
+    };
+    assert_eq!(
+        batch_ssh_args(&opt, "10.0.0.1", &["uptime".to_string()]),
+        vec![
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "ConnectTimeout=10",
+            "10.0.0.1",
+            "uptime"
+        ]
+    );
+}
+
+// a Host block of ssh_config
+pub fn ssh_config_block(alias: &str, host: &str, opt: &SshOpt) -> String {
+    let mut lines = vec![format!("Host {}", alias), format!("    HostName {}", host)];
+    let options = [
+        ("User", &opt.user),
+        ("IdentityFile", &opt.identity),
+        ("ProxyJump", &opt.jump),
+    ];
+    for (key, value) in options {
+        if let Some(v) = value {
+            lines.push(format!("    {} {}", key, v));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+#[test]
+fn test_ssh_config_block() {
+    let opt = SshOpt {
+        address: "private_ip".to_string(),
+        user: Some("ec2-user".to_string()),
+        identity: None,
+        jump: Some("bastion".to_string()),
+    };
+    assert_eq!(
+        ssh_config_block("api-web", "10.0.0.1", &opt),
+        "Host api-web\n    HostName 10.0.0.1\n    User ec2-user\n    ProxyJump bastion\n"
+    );
+}
+
+// how to start a session manager session
+#[derive(Debug, StructOpt)]
+pub struct SsmOpt {
+    #[structopt(
+        long = "remote-port",
+        help = "forward a local port to this port of the instance (or of --remote-host)"
+    )]
+    pub remote_port: Option<u16>,
+    #[structopt(
+        long = "local-port",
+        requires = "remote-port",
+        help = "the local port to forward. the same as --remote-port if not set"
+    )]
+    pub local_port: Option<u16>,
+    #[structopt(
+        long = "remote-host",
+        requires = "remote-port",
+        help = "forward to this host through the instance. e.g. an RDS endpoint"
+    )]
+    pub remote_host: Option<String>,
+}
+
+// arguments of `aws` to start a session on the target instance
+pub fn ssm_args(
+    opt: &SsmOpt,
+    target: &str,
+    region: &str,
+    profile: &str,
+    endpoint: &str,
+) -> Vec<String> {
+    let mut args: Vec<String> = vec!["ssm", "start-session", "--target", target]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if let Some(remote) = opt.remote_port {
+        let local = opt.local_port.unwrap_or(remote);
+        let mut parameters = json!({
+            "portNumber": [remote.to_string()],
+            "localPortNumber": [local.to_string()],
+        });
+        let document = match &opt.remote_host {
+            Some(host) => {
+                parameters["host"] = json!([host]);
+                "AWS-StartPortForwardingSessionToRemoteHost"
+            }
+            None => "AWS-StartPortForwardingSession",
+        };
+        args.push("--document-name".to_string());
+        args.push(document.to_string());
+        args.push("--parameters".to_string());
+        args.push(parameters.to_string());
+    }
+    for (flag, value) in [
+        ("--region", region),
+        ("--profile", profile),
+        ("--endpoint-url", endpoint),
+    ] {
+        if !value.is_empty() {
+            args.push(flag.to_string());
+            args.push(value.to_string());
+        }
+    }
+    args
+}
+#[test]
+fn test_ssm_args() {
+    let mut opt = SsmOpt {
+        remote_port: None,
+        local_port: None,
+        remote_host: None,
+    };
+    assert_eq!(
+        ssm_args(&opt, "i-1", "us-west-2", "", ""),
+        vec![
+            "ssm",
+            "start-session",
+            "--target",
+            "i-1",
+            "--region",
+            "us-west-2"
+        ]
+    );
+    opt.remote_port = Some(5432);
+    opt.remote_host = Some("db.internal".to_string());
+    let args = ssm_args(&opt, "i-1", "", "prod", "http://localhost:4566");
+    assert_eq!(args[5], "AWS-StartPortForwardingSessionToRemoteHost");
+    assert_eq!(
+        args[7],
+        r#"{"host":["db.internal"],"localPortNumber":["5432"],"portNumber":["5432"]}"#
+    );
+    assert_eq!(
+        &args[8..],
+        [
+            "--profile",
+            "prod",
+            "--endpoint-url",
+            "http://localhost:4566"
+        ]
+    );
+}
+
+// replace this process with the command, so that the terminal is handed over as is
+pub fn exec(command: &mut Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().to_string();
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let err = command.exec();
+        Err(Error::Invalid(format!(
+            "failed to run {}: {}",
+            program, err
+        )))
+    }
+    #[cfg(not(unix))]
+    {
+        let status = command
+            .status()
+            .map_err(|err| Error::Invalid(format!("failed to run {}: {}", program, err)))?;
+        std::process::exit(status.code().unwrap_or(1))
+    }
+}
+
+// run the command with output lines prefixed, waiting for a permit of the limit. returns the exit code
+pub async fn run_prefixed(
+    prefix: String,
+    mut command: tokio::process::Command,
+    limit: &Semaphore,
+) -> Result<i32> {
+    let _permit = limit
+        .acquire()
+        .await
+        .map_err(|e| Error::Api(e.to_string()))?;
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::Invalid(format!("failed to run {}: {}", program, err)))?;
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    tokio::try_join!(
+        print_lines(&prefix, stdout, false),
+        print_lines(&prefix, stderr, true)
+    )?;
+    let status = child.wait().await?;
+    // killed by a signal
+    Ok(status.code().unwrap_or(255))
+}
+
+async fn print_lines(
+    prefix: &str,
+    reader: impl AsyncBufRead + Unpin,
+    stderr: bool,
+) -> std::io::Result<()> {
+    let mut lines = reader.lines();
+    while let Some(line) = lines.next_line().await? {
+        if stderr {
+            eprintln!("{} | {}", prefix, line);
+        } else {
+            println!("{} | {}", prefix, line);
+        }
+    }
+    Ok(())
+}
+
+// print exit codes of hosts to stderr. fails when the command failed on any host or ran on none
+pub fn exit_summary(results: &[(String, i32)]) -> Result<()> {
+    // e.g. none of the instances has the address to connect to
+    if results.is_empty() {
+        return Err(Error::Remote("ran on no instances".to_string()));
+    }
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|(_, code)| *code != 0)
+        .map(|(host, code)| format!("{}({})", host, code))
+        .collect();
+    eprintln!(
+        "succeeded: {}, failed: {}",
+        results.len() - failed.len(),
+        failed.len()
+    );
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Remote(format!("failed on {}", failed.join(", "))))
+}
+#[test]
+fn test_exit_summary() {
+    let results = |codes: &[i32]| -> Vec<(String, i32)> {
+        codes
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (format!("host{}", i), *c))
+            .collect()
+    };
+    assert_eq!(exit_summary(&results(&[0, 0])), Ok(()));
+    assert!(exit_summary(&results(&[])).is_err());
+    assert_eq!(
+        exit_summary(&results(&[0, 1, 255])),
+        Err(Error::Remote("failed on host1(1), host2(255)".to_string()))
+    );
+}
@@ -1,10 +1,25 @@
-use crate::awsutils::{config, GlobalOpt};
-use crate::utils::{get_values, print_table, split, Tag};
+use crate::awsutils::{
+    clients, datetime_rfc3339, merge_scoped, scope_client, GlobalOpt, Scope, ScopeColumns, Scoped,
+};
+use crate::cache::{cached, Cached};
+use crate::error::{found, Error, Result};
+use crate::filter::{apply, Filter};
+use crate::output::{print, OutputOpt};
+use crate::picker::choose;
+use crate::query::{Matcher, Searchable};
+use crate::utils::{get_values, parse_duration, split, Record, Tag};
+use crate::watch::{watch, WatchOpt};
 use aws_sdk_elasticloadbalancingv2::Client;
+use aws_smithy_types::DateTime;
+use futures::future::join_all;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
+use tokio::time::Instant;
 
 #[derive(Debug, StructOpt)]
 pub enum TargetGroupOpt {
@@ -16,7 +31,72 @@ pub enum TargetGroupOpt {
     Port(SearchQueryOpt),
 
     #[structopt(about = "get target healths")]
-    Health(SearchQueryOpt),
+    Health(HealthOpt),
+    #[structopt(about = "wait until targets are healthy")]
+    Wait(WaitOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HealthOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(
+        long,
+        help = "show target healths of every matched target group, grouped by name. \
+    otherwise, choose one interactively when several match"
+    )]
+    all: bool,
+    #[structopt(flatten)]
+    watch: WatchOpt,
+}
+
+// states of describe_target_health
+const TARGET_STATES: &[&str] = &[
+    "initial",
+    "healthy",
+    "unhealthy",
+    "unused",
+    "draining",
+    "unavailable",
+];
+
+#[derive(Debug, StructOpt)]
+pub struct WaitOpt {
+    #[structopt(flatten)]
+    query: SearchQueryOpt,
+    #[structopt(
+        long,
+        help = "wait for the targets of every matched target group. \
+    otherwise, choose one interactively when several match"
+    )]
+    all: bool,
+    #[structopt(
+        long,
+        default_value = "healthy",
+        possible_values = TARGET_STATES,
+        help = "the state to wait for"
+    )]
+    until: String,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "wait until at least N targets are in the state, instead of all of them"
+    )]
+    min: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "10m",
+        parse(try_from_str = parse_duration),
+        help = "give up after this time. e.g. 90s, 10m, 1h"
+    )]
+    timeout: Duration,
+    #[structopt(
+        long,
+        value_name = "seconds",
+        default_value = "5",
+        help = "seconds between polls"
+    )]
+    interval: u64,
 }
 
 #[derive(Debug, StructOpt)]
@@ -27,6 +107,28 @@ pub struct SearchQueryOpt {
         help = "ambiguous search with asterisk on target group name or ALB arn.  if set comma, search OR"
     )]
     query: Option<String>,
+    #[structopt(
+        short = "e",
+        long,
+        conflicts_with = "regex",
+        help = "exact search instead of ambiguous search. asterisk can be used as wildcard"
+    )]
+    exact: bool,
+    #[structopt(
+        short = "r",
+        long,
+        help = "regex search on target group name or load balancer. use | instead of comma for OR"
+    )]
+    regex: bool,
+    #[structopt(
+        short = "f",
+        long = "filter",
+        number_of_values = 1,
+        help = "filter with field expressions. if set multiple, search AND.
+    field=value (asterisk as wildcard), field~regex, field (exists), !expr (not)
+    e.g. -f type=instance -f tag:env=prod -f 'name~^api' -f '!tag:ignore-monitor'"
+    )]
+    filters: Vec<Filter>,
     #[structopt(
         short = "T",
         long,
@@ -38,19 +140,24 @@ pub struct SearchQueryOpt {
     show_all_tags: bool,
 }
 
-pub async fn matcher(global_opt: GlobalOpt, opt: TargetGroupOpt) {
-    let cli = Client::new(&config(global_opt).await);
+pub async fn matcher(global_opt: GlobalOpt, opt: TargetGroupOpt) -> Result<()> {
+    let clis = clients::<Client>(&global_opt).await?;
+    let output = &global_opt.output;
     match opt {
-        TargetGroupOpt::Info(opt) => info(&cli, opt).await,
-        TargetGroupOpt::LoadBalancerArn(opt) => load_balancer_arn(&cli, opt).await,
-        TargetGroupOpt::Port(opt) => port(&cli, opt).await,
-        TargetGroupOpt::Health(opt) => target_health(&cli, opt).await,
+        TargetGroupOpt::Info(mut opt) => {
+            opt.tag_columns = opt.tag_columns.or(global_opt.tag_columns);
+            info(&clis, output, opt).await
+        }
+        TargetGroupOpt::LoadBalancerArn(opt) => load_balancer_arn(&clis, output, opt).await,
+        TargetGroupOpt::Port(opt) => port(&clis, output, opt).await,
+        TargetGroupOpt::Health(opt) => target_health(&clis, output, opt).await,
+        TargetGroupOpt::Wait(opt) => wait(&clis, opt).await,
     }
 }
 
-async fn info(cli: &Client, opt: SearchQueryOpt) {
-    let tgs = get_target_groups(cli, &opt).await;
-    let len = tgs.len();
+async fn info(clis: &[Scoped<Client>], output: &OutputOpt, opt: SearchQueryOpt) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let tgs = get_target_groups(clis, &opt).await?;
 
     let tag_column: Vec<String> = if opt.show_all_tags {
         tgs.iter()
@@ -58,83 +165,282 @@ async fn info(cli: &Client, opt: SearchQueryOpt) {
             .unique()
             .collect()
     } else {
-        opt.tag_columns
-            .map(|t| split(&*t, true))
-            .unwrap_or_default()
+        opt.tag_columns.map(|t| split(&t, true)).unwrap_or_default()
     };
 
     let rows: Vec<Vec<String>> = tgs
-        .into_iter()
+        .iter()
         .map(|t| {
             let r = get_values(&t.tags, &tag_column);
-            vec![
-                t.name,
-                t.target_type,
-                t.lb.map(|l| format!("{:?}", l)).unwrap_or_default(),
-            ]
-            .into_iter()
-            .chain(r)
-            .collect()
+            let row = vec![
+                t.name.clone(),
+                t.target_type.clone(),
+                t.lb.as_ref()
+                    .map(|l| format!("{:?}", l))
+                    .unwrap_or_default(),
+            ];
+            columns.row(&t.scope, row.into_iter().chain(r).collect())
         })
         .collect();
-    let header: Vec<String> = vec![
-        "Name".to_string(),
-        "TargetType".to_string(),
-        "LB".to_string(),
-    ]
-    .into_iter()
-    .chain(tag_column)
-    .collect();
-    print_table(header, rows);
-    println!("counts: {}", len);
+    let header: Vec<String> = columns.header(
+        vec![
+            "Name".to_string(),
+            "TargetType".to_string(),
+            "LB".to_string(),
+        ]
+        .into_iter()
+        .chain(tag_column)
+        .collect(),
+    );
+    print(output, &tgs, header, rows)?;
+    found(&tgs, "target groups")
 }
 
-async fn load_balancer_arn(cli: &Client, opt: SearchQueryOpt) {
-    let tgs = get_target_groups(cli, &opt).await;
-    let len = tgs.len();
+async fn load_balancer_arn(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    opt: SearchQueryOpt,
+) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let tgs = get_target_groups(clis, &opt).await?;
     let rows: Vec<Vec<String>> = tgs
-        .into_iter()
+        .iter()
         .map(|t| {
-            vec![
-                t.name,
-                t.lb_arn.map(|l| format!("{:?}", l)).unwrap_or_default(),
-            ]
+            columns.row(
+                &t.scope,
+                vec![
+                    t.name.clone(),
+                    t.lb_arn
+                        .as_ref()
+                        .map(|l| format!("{:?}", l))
+                        .unwrap_or_default(),
+                ],
+            )
         })
         .collect();
-    print_table(vec!["Name".to_string(), "LB arn".to_string()], rows);
-    println!("counts: {}", len);
+    print(
+        output,
+        &tgs,
+        columns.header(vec!["Name".to_string(), "LB arn".to_string()]),
+        rows,
+    )?;
+    found(&tgs, "target groups")
 }
 
-async fn port(cli: &Client, opt: SearchQueryOpt) {
-    let tgs = get_target_groups(cli, &opt).await;
-    let len = tgs.len();
+async fn port(clis: &[Scoped<Client>], output: &OutputOpt, opt: SearchQueryOpt) -> Result<()> {
+    let columns = ScopeColumns::new(clis.iter().map(|c| &c.scope));
+    let tgs = get_target_groups(clis, &opt).await?;
     let rows: Vec<Vec<String>> = tgs
-        .into_iter()
-        .map(|t| vec![t.name, format!("{}", t.port)])
+        .iter()
+        .map(|t| columns.row(&t.scope, vec![t.name.clone(), format!("{}", t.port)]))
         .collect();
-    print_table(vec!["Name".to_string(), "Port".to_string()], rows);
-    println!("counts: {}", len);
+    print(
+        output,
+        &tgs,
+        columns.header(vec!["Name".to_string(), "Port".to_string()]),
+        rows,
+    )?;
+    found(&tgs, "target groups")
 }
 
-async fn target_health(cli: &Client, opt: SearchQueryOpt) {
-    let tgs = get_target_groups(cli, &opt).await;
-    if tgs.len() != 1 {
-        println!("need to be narrowed to 1");
-        return;
+async fn target_health(clis: &[Scoped<Client>], output: &OutputOpt, opt: HealthOpt) -> Result<()> {
+    let tgs = get_target_groups(clis, &opt.query).await?;
+    let targets = chosen(&tgs, opt.all)?;
+    // the target groups are chosen once, and only their healths are redrawn
+    let output = &opt.watch.output(output);
+    watch(&opt.watch, || {
+        print_target_health(clis, output, &targets, opt.all)
+    })
+    .await
+}
+
+// every matched target group sorted by name, or the one chosen interactively
+fn chosen(tgs: &[TargetGroup], all: bool) -> Result<Vec<&TargetGroup>> {
+    if all {
+        found(tgs, "target groups")?;
+        Ok(tgs.iter().sorted_by(|a, b| a.name.cmp(&b.name)).collect())
+    } else {
+        Ok(vec![choose(tgs, "target groups", label)?])
     }
-    let h = get_target_health(cli, tgs.first().unwrap().arn.clone()).await;
-    let len = h.len();
+}
+
+async fn fetch_target_health(
+    clis: &[Scoped<Client>],
+    targets: &[&TargetGroup],
+) -> Result<Vec<TargetHealth>> {
+    Ok(join_all(targets.iter().map(|tg| async move {
+        let cli = scope_client(clis, &tg.scope)?;
+        get_target_health(&cli.client, tg).await
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?
+    .into_iter()
+    .flatten()
+    .collect())
+}
+
+async fn print_target_health(
+    clis: &[Scoped<Client>],
+    output: &OutputOpt,
+    targets: &[&TargetGroup],
+    all: bool,
+) -> Result<()> {
+    let h = fetch_target_health(clis, targets).await?;
+
+    let columns = ScopeColumns::new(targets.iter().map(|t| &t.scope));
+    let group_column = |name: &str| {
+        if all {
+            vec![name.to_string()]
+        } else {
+            vec![]
+        }
+    };
     let rows: Vec<Vec<String>> = h
+        .iter()
+        .map(|t| {
+            let row = vec![t.id.clone(), t.port.clone(), t.status.clone()];
+            columns.row(
+                &t.scope,
+                group_column(&t.target_group)
+                    .into_iter()
+                    .chain(row)
+                    .collect(),
+            )
+        })
+        .collect();
+    let header = group_column("TargetGroup")
         .into_iter()
-        .map(|t| vec![t.id, t.port, t.status])
+        .chain(vec![
+            "ID".to_string(),
+            "Port".to_string(),
+            "Status".to_string(),
+        ])
         .collect();
-    print_table(
-        vec!["ID".to_string(), "Port".to_string(), "Status".to_string()],
-        rows,
+    print(output, &h, columns.header(header), rows)?;
+    Ok(())
+}
+
+// poll target healths until the condition holds, printing each change of a target
+async fn wait(clis: &[Scoped<Client>], opt: WaitOpt) -> Result<()> {
+    let tgs = get_target_groups(clis, &opt.query).await?;
+    let targets = chosen(&tgs, opt.all)?;
+    let deadline = Instant::now() + opt.timeout;
+    let mut states: HashMap<String, String> = HashMap::new();
+    // targets in the state and all targets of the last poll
+    let mut counts = (0, 0);
+    loop {
+        match fetch_target_health(clis, &targets).await {
+            Ok(h) => {
+                let now = datetime_rfc3339(DateTime::from(SystemTime::now()));
+                for t in transitions(&mut states, &h) {
+                    println!("{}  {}", now, t);
+                }
+                counts = (h.iter().filter(|t| t.status == opt.until).count(), h.len());
+                if satisfied(counts.0, counts.1, opt.min) {
+                    println!("{}/{} targets are {}", counts.0, counts.1, opt.until);
+                    return Ok(());
+                }
+            }
+            // a gate of a deploy shouldn't fail only because of the rate limit
+            Err(e @ Error::Throttling(_)) => eprintln!("Warning: {}", e),
+            Err(e) => return Err(e),
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::Timeout(format!(
+                "{}/{} targets are {} after {}s",
+                counts.0,
+                counts.1,
+                opt.until,
+                opt.timeout.as_secs()
+            )));
+        }
+        tokio::time::sleep(left.min(Duration::from_secs(opt.interval.max(1)))).await;
+    }
+}
+
+// all targets, or at least min targets, are in the state
+fn satisfied(count: usize, total: usize, min: Option<usize>) -> bool {
+    match min {
+        Some(n) => count >= n,
+        None => total > 0 && count == total,
+    }
+}
+#[test]
+fn test_satisfied() {
+    assert!(satisfied(2, 2, None));
+    assert!(!satisfied(1, 2, None));
+    assert!(!satisfied(0, 0, None));
+    assert!(satisfied(1, 2, Some(1)));
+    assert!(!satisfied(1, 2, Some(2)));
+}
+
+// lines for targets which are new, changed or deregistered since the previous poll
+fn transitions(states: &mut HashMap<String, String>, current: &[TargetHealth]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut seen = vec![];
+    for t in current {
+        let key = format!("{} {}:{}", t.target_group, t.id, t.port);
+        match states.insert(key.clone(), t.status.clone()) {
+            Some(p) if p == t.status => {}
+            Some(p) => lines.push(format!("{}  {} -> {}", key, p, t.status)),
+            None => lines.push(format!("{}  {}", key, t.status)),
+        }
+        seen.push(key);
+    }
+    let gone: Vec<String> = states
+        .keys()
+        .filter(|k| !seen.contains(k))
+        .sorted()
+        .cloned()
+        .collect();
+    for key in gone {
+        let p = states.remove(&key).unwrap_or_default();
+        lines.push(format!("{}  {} -> deregistered", key, p));
+    }
+    lines
+}
+#[test]
+fn test_transitions() {
+    let health = |id: &str, status: &str| TargetHealth {
+        target_group: "api".to_string(),
+        id: id.to_string(),
+        port: "80".to_string(),
+        status: status.to_string(),
+        scope: Scope::default(),
+    };
+    let mut states = HashMap::new();
+    assert_eq!(
+        transitions(&mut states, &[health("i-1", "initial")]),
+        vec!["api i-1:80  initial"]
     );
-    println!("counts: {}", len);
+    assert!(transitions(&mut states, &[health("i-1", "initial")]).is_empty());
+    assert_eq!(
+        transitions(
+            &mut states,
+            &[health("i-1", "healthy"), health("i-2", "initial")]
+        ),
+        vec!["api i-1:80  initial -> healthy", "api i-2:80  initial"]
+    );
+    assert_eq!(
+        transitions(&mut states, &[health("i-2", "initial")]),
+        vec!["api i-1:80  healthy -> deregistered"]
+    );
+}
+
+// a line for the interactive picker
+fn label(t: &TargetGroup) -> String {
+    format!(
+        "{}  {}  {}  {}",
+        t.name,
+        t.target_type,
+        t.lb.as_ref().map(|l| l.join(",")).unwrap_or_default(),
+        t.scope.region
+    )
 }
 
+#[derive(Serialize, Deserialize)]
 struct TargetGroup {
     name: String,
     port: i32,
@@ -143,175 +449,210 @@ struct TargetGroup {
     lb: Option<Vec<String>>,
     lb_arn: Option<Vec<String>>,
     tags: Vec<Tag>,
+    #[serde(flatten)]
+    scope: Scope,
+}
+async fn get_target_groups(
+    clis: &[Scoped<Client>],
+    opt: &SearchQueryOpt,
+) -> Result<Vec<TargetGroup>> {
+    let matcher = Matcher::new(opt.query.as_deref(), opt.exact, opt.regex)?;
+    let tgs = join_all(clis.iter().map(|c| scoped_target_groups(c, &matcher))).await;
+    Ok(apply(&opt.filters, merge_scoped(clis, tgs)?)?)
 }
-async fn get_target_groups(cli: &Client, opt: &SearchQueryOpt) -> Vec<TargetGroup> {
+
+async fn scoped_target_groups(cli: &Scoped<Client>, matcher: &Matcher) -> Result<Vec<TargetGroup>> {
+    // tags of all target groups are worth fetching only when the cache is read back.
+    // otherwise only the matched ones are tagged, and the names are cached for completion
+    if cli.cache.ttl.is_zero() && !cli.cache.offline {
+        let tgs = cached(
+            &cli.cache,
+            &cli.scope,
+            "target_groups.untagged",
+            true,
+            fetch_target_groups(cli),
+        );
+        let matched = tgs.await?.into_iter().filter(|t| matcher.matches(t));
+        return set_tags(&cli.client, matched.collect()).await;
+    }
+    let tgs = cached(&cli.cache, &cli.scope, "target_groups", true, async {
+        set_tags(&cli.client, fetch_target_groups(cli).await?).await
+    });
+    Ok(tgs
+        .await?
+        .into_iter()
+        .filter(|t| matcher.matches(t))
+        .collect())
+}
+
+// all target groups without tags
+async fn fetch_target_groups(cli: &Scoped<Client>) -> Result<Vec<TargetGroup>> {
     let mut m: Option<String> = None;
     let mut vector: Vec<TargetGroup> = vec![];
     loop {
-        let (mut v, mark) = target_group(cli, &m).await;
+        let (mut v, mark) = target_group(cli, &m).await?;
         m = mark;
         vector.append(&mut v);
         if m.is_none() {
             break;
         }
     }
-    let tgs = vector
-        .into_iter()
-        .filter(|t| search_name(&opt.query, &t.name, &t.lb_arn))
-        .collect();
-    set_tags(cli, tgs).await
+    Ok(vector)
 }
 
 async fn target_group(
-    client: &Client,
+    cli: &Scoped<Client>,
     marker: &Option<String>,
-) -> (Vec<TargetGroup>, Option<String>) {
-    match client
+) -> Result<(Vec<TargetGroup>, Option<String>)> {
+    let res = cli
+        .client
         .describe_target_groups()
         .set_marker(marker.clone())
         .send()
-        .await
-    {
-        Ok(res) => {
-            let tgs = res.target_groups.unwrap_or_default();
-            (
-                tgs.into_iter()
-                    .map(|t| TargetGroup {
-                        name: t.target_group_name.unwrap_or_default(),
-                        port: t.port.unwrap_or_default(),
-                        arn: t.target_group_arn.unwrap_or_default(),
-                        target_type: t
-                            .target_type
-                            .map(|t| t.as_str().to_string())
-                            .unwrap_or_default(),
-                        lb: t
-                            .load_balancer_arns
-                            .as_ref()
-                            .map(|v| v.iter().map(|arn| extract_lb_name(arn)).collect()),
-                        lb_arn: t.load_balancer_arns,
-                        tags: vec![],
-                    })
-                    .collect(),
-                res.next_marker,
-            )
-        }
-        Err(err) => panic!("{}", err.to_string()),
+        .await?;
+    let tgs = res.target_groups.unwrap_or_default();
+    Ok((
+        tgs.into_iter()
+            .map(|t| TargetGroup {
+                name: t.target_group_name.unwrap_or_default(),
+                port: t.port.unwrap_or_default(),
+                arn: t.target_group_arn.unwrap_or_default(),
+                target_type: t
+                    .target_type
+                    .map(|t| t.as_str().to_string())
+                    .unwrap_or_default(),
+                lb: t
+                    .load_balancer_arns
+                    .as_ref()
+                    .map(|v| v.iter().map(|arn| extract_lb_name(arn)).collect()),
+                lb_arn: t.load_balancer_arns,
+                tags: vec![],
+                scope: cli.scope.clone(),
+            })
+            .collect(),
+        res.next_marker,
+    ))
+}
+
+impl Cached for TargetGroup {
+    fn set_scope(&mut self, scope: &Scope) {
+        self.scope = scope.clone();
     }
 }
 
-fn search_name(query: &Option<String>, tg_name: &str, lb_arn: &Option<Vec<String>>) -> bool {
-    if query.is_none() || tg_name.is_empty() {
-        return true;
+impl Record for TargetGroup {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "name" => self.name.clone(),
+            "port" => self.port.to_string(),
+            "arn" => self.arn.clone(),
+            "target_type" | "type" => self.target_type.clone(),
+            "lb" => self.lb.as_ref().map(|l| l.join(",")).unwrap_or_default(),
+            "lb_arn" => self
+                .lb_arn
+                .as_ref()
+                .map(|l| l.join(","))
+                .unwrap_or_default(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
     }
-    let tg: String = tg_name.to_string();
-    let qu: String = query.as_ref().unwrap().clone();
-    let lb: String = lb_arn.as_ref().map(|lv| lv.join(",")).unwrap_or_default();
-    for q in qu.split(',') {
-        if tg.contains(q) || lb.contains(q) {
-            return true;
-        }
+    fn tags(&self) -> &[Tag] {
+        &self.tags
     }
+}
 
-    false
+impl Searchable for TargetGroup {
+    fn search_keys(&self) -> Vec<&str> {
+        let lb = self.lb.iter().flatten();
+        let lb_arn = self.lb_arn.iter().flatten();
+        std::iter::once(&self.name)
+            .chain(lb)
+            .chain(lb_arn)
+            .map(|s| s.as_str())
+            .collect()
+    }
 }
 
-async fn set_tags(client: &Client, tgs: Vec<TargetGroup>) -> Vec<TargetGroup> {
+async fn set_tags(client: &Client, mut tgs: Vec<TargetGroup>) -> Result<Vec<TargetGroup>> {
+    // DescribeTags accepts up to 20 arns at once
     const WINDOW: usize = 20;
-    let mut arns = tgs.iter().map(|t| t.arn.clone());
-    let mut offset = 0;
-    let mut vector: Vec<TargetGroup> = vec![];
-    loop {
-        if offset > tgs.len() {
-            break;
-        }
-        offset += WINDOW;
-        let resource_arns: Vec<String> = arns.by_ref().take(offset).collect();
-        match client
+    for chunk in tgs.chunks_mut(WINDOW) {
+        let resource_arns: Vec<String> = chunk.iter().map(|t| t.arn.clone()).collect();
+        let res = client
             .describe_tags()
             .set_resource_arns(Some(resource_arns))
             .send()
-            .await
-        {
-            Ok(res) => res.tag_descriptions.into_iter().for_each(|vt| {
-                vt.into_iter().for_each(|td| {
-                    let target_op = tgs
-                        .iter()
-                        .find(|tg| td.resource_arn == Some(tg.arn.clone()));
-                    if target_op.is_some() {
-                        let tags = td
-                            .tags
-                            .map(|ot| {
-                                ot.into_iter()
-                                    .map(|t| Tag {
-                                        key: t.key.unwrap_or_default(),
-                                        value: t.value,
-                                    })
-                                    .collect()
-                            })
-                            .unwrap_or_default();
-                        let t = target_op
-                            .map(|t| TargetGroup {
-                                name: t.name.clone(),
-                                port: t.port,
-                                arn: t.arn.clone(),
-                                target_type: t.target_type.clone(),
-                                lb: t.lb.clone(),
-                                lb_arn: t.lb_arn.clone(),
-                                tags,
+            .await?;
+        for td in res.tag_descriptions.unwrap_or_default() {
+            let target = chunk
+                .iter_mut()
+                .find(|tg| td.resource_arn.as_deref() == Some(tg.arn.as_str()));
+            if let Some(t) = target {
+                t.tags = td
+                    .tags
+                    .map(|ot| {
+                        ot.into_iter()
+                            .map(|t| Tag {
+                                key: t.key.unwrap_or_default(),
+                                value: t.value,
                             })
-                            .unwrap();
-                        vector.push(t)
-                    }
-                })
-            }),
-            Err(err) => panic!("{}", err.to_string()),
+                            .collect()
+                    })
+                    .unwrap_or_default();
+            }
         }
     }
-    vector
+    Ok(tgs)
 }
 #[test]
-fn test_search_name() {
-    assert_eq!(
-        search_name(&Some("api".to_string()), &"aa".to_string(), &None),
-        false
-    );
-    assert_eq!(
-        search_name(
-            &Some("aa".to_string()),
-            &"".to_string(),
-            &Some(vec!["aa".to_string()])
-        ),
-        true
-    );
+fn test_search() {
+    let tg = |name: &str, lb_arn: Option<Vec<String>>| TargetGroup {
+        name: name.to_string(),
+        port: 80,
+        arn: "".to_string(),
+        target_type: "instance".to_string(),
+        lb: lb_arn
+            .as_ref()
+            .map(|v| v.iter().map(|arn| extract_lb_name(arn)).collect()),
+        lb_arn,
+        tags: vec![],
+        scope: Scope::default(),
+    };
+    let search = |q: &str, exact: bool, t: &TargetGroup| {
+        Matcher::new(Some(q), exact, false).unwrap().matches(t)
+    };
+    assert!(!search("api", false, &tg("aa", None)));
+    assert!(search("aa", false, &tg("", Some(vec!["aa".to_string()]))));
+    assert!(search("api,test", false, &tg("test-api", None)));
+    assert!(!search("api", false, &tg("ap", None)));
+    assert!(search(
+        "api",
+        false,
+        &tg("ap", Some(vec!["api-lb".to_string()]))
+    ));
 
-    assert_eq!(
-        search_name(
-            &Some("api,test".to_string()),
-            &"test-api".to_string(),
-            &None
-        ),
-        true
-    );
-    assert_eq!(
-        search_name(&Some("api".to_string()), &"ap".to_string(), &None),
-        false
-    );
-    assert_eq!(
-        search_name(
-            &Some("api".to_string()),
-            &"ap".to_string(),
-            &Some(vec!["api-lb".to_string()])
-        ),
-        true
-    );
+    let alb =
+        "arn:aws:elasticloadbalancing:ap-northeast-1:11111111:loadbalancer/app/api-alb/abcdefg123";
+    assert!(search(
+        "api-alb",
+        true,
+        &tg("web", Some(vec![alb.to_string()]))
+    ));
+    assert!(!search(
+        "api",
+        true,
+        &tg("api-canary", Some(vec![alb.to_string()]))
+    ));
+    assert!(search("api", true, &tg("api", None)));
 }
 static ALB: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+loadbalancer/app/(.+)/.+$").unwrap());
 static NLB: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+loadbalancer/net/(.+)/.+$").unwrap());
 fn extract_lb_name(lb_arn: &str) -> String {
-    if lb_arn.contains(&"loadbalancer/app/") {
+    if lb_arn.contains("loadbalancer/app/") {
         let c = ALB.captures(lb_arn).unwrap();
         return c[1].to_string();
-    } else if lb_arn.contains(&"loadbalancer/net/") {
+    } else if lb_arn.contains("loadbalancer/net/") {
         let c = NLB.captures(lb_arn).unwrap();
         return c[1].to_string();
     }
@@ -320,38 +661,58 @@ fn extract_lb_name(lb_arn: &str) -> String {
 #[test]
 fn test_extract_lb_name() {
     assert_eq!(
-        extract_lb_name(&"arn:aws:elasticloadbalancing:ap-northeast-1:11111111:loadbalancer/app/api-alb/abcdefg123".to_string()),
+        extract_lb_name("arn:aws:elasticloadbalancing:ap-northeast-1:11111111:loadbalancer/app/api-alb/abcdefg123"),
         "api-alb".to_string());
     assert_eq!(
-        extract_lb_name(&"arn:aws:elasticloadbalancing:ap-northeast-1:11111111:loadbalancer/net/api-alb/abcdefg123".to_string()),
+        extract_lb_name("arn:aws:elasticloadbalancing:ap-northeast-1:11111111:loadbalancer/net/api-alb/abcdefg123"),
         "api-alb".to_string());
 }
 
+#[derive(Serialize)]
 struct TargetHealth {
+    target_group: String,
     id: String,
     port: String,
     status: String,
+    #[serde(flatten)]
+    scope: Scope,
+}
+
+impl Record for TargetHealth {
+    fn attribute(&self, key: &str) -> Option<String> {
+        let v = match key {
+            "target_group" => self.target_group.clone(),
+            "id" => self.id.clone(),
+            "port" => self.port.clone(),
+            "status" => self.status.clone(),
+            _ => return self.scope.attribute(key),
+        };
+        Some(v)
+    }
+    fn tags(&self) -> &[Tag] {
+        &[]
+    }
 }
-async fn get_target_health(cli: &Client, arn: String) -> Vec<TargetHealth> {
-    match cli
+
+async fn get_target_health(cli: &Client, tg: &TargetGroup) -> Result<Vec<TargetHealth>> {
+    let res = cli
         .describe_target_health()
-        .target_group_arn(arn)
+        .target_group_arn(tg.arn.clone())
         .send()
-        .await
-    {
-        Ok(res) => res
-            .target_health_descriptions
-            .unwrap_or_default()
-            .into_iter()
-            .map(|h| TargetHealth {
-                id: h.target.and_then(|t| t.id).unwrap_or_default(),
-                port: h.health_check_port.unwrap_or_default(),
-                status: h
-                    .target_health
-                    .and_then(|t| t.state.map(|s| s.as_str().to_string()))
-                    .unwrap_or_default(),
-            })
-            .collect(),
-        Err(err) => panic!("{}", err.to_string()),
-    }
+        .await?;
+    Ok(res
+        .target_health_descriptions
+        .unwrap_or_default()
+        .into_iter()
+        .map(|h| TargetHealth {
+            target_group: tg.name.clone(),
+            id: h.target.and_then(|t| t.id).unwrap_or_default(),
+            port: h.health_check_port.unwrap_or_default(),
+            status: h
+                .target_health
+                .and_then(|t| t.state.map(|s| s.as_str().to_string()))
+                .unwrap_or_default(),
+            scope: tg.scope.clone(),
+        })
+        .collect())
 }
@@ -1,5 +1,7 @@
 use cli_table::format::{Border, Separator};
-use cli_table::{print_stdout, Cell, CellStruct, Style, Table, TableStruct};
+use cli_table::{print_stdout, Cell, CellStruct, Color, Style, Table, TableStruct};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 pub fn name_query(query: &Option<String>, exact_q: &Option<String>) -> Option<Vec<String>> {
     let input = query.as_ref().map(|q| split(q, false));
@@ -18,7 +20,7 @@ pub fn split(q: &str, is_exact: bool) -> Vec<String> {
             format!("*{}*", s)
         }
     };
-    q.split(',').map(|s| format(s)).collect()
+    q.split(',').map(format).collect()
 }
 
 #[test]
@@ -37,11 +39,51 @@ fn test_name_query() {
     );
 }
 
-pub fn print_table(header: Vec<String>, rows: Vec<Vec<String>>) {
+// 90, 90s, 10m or 1h
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("invalid duration '{}'. e.g. 90s, 10m, 1h", s)),
+    };
+    n.parse::<u64>()
+        .map(|n| Duration::from_secs(n * secs))
+        .map_err(|_| format!("invalid duration '{}'. e.g. 90s, 10m, 1h", s))
+}
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+    assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+    assert!(parse_duration("10 m").is_err());
+    assert!(parse_duration("m").is_err());
+    assert!(parse_duration("").is_err());
+}
+
+pub fn print_table(header: Vec<String>, rows: Vec<Vec<String>>) -> std::io::Result<()> {
+    print_table_highlighted(header, rows, &[])
+}
+
+// rows whose flag is true are drawn in yellow
+pub fn print_table_highlighted(
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+    highlighted: &[bool],
+) -> std::io::Result<()> {
     let h: Vec<CellStruct> = header.iter().map(|h| h.cell().bold(true)).collect();
     let rows: Vec<Vec<CellStruct>> = rows
         .iter()
-        .map(|r| r.iter().map(|c| c.cell()).collect())
+        .enumerate()
+        .map(|(i, r)| {
+            let color = Some(Color::Yellow).filter(|_| highlighted.get(i) == Some(&true));
+            r.iter().map(|c| c.cell().foreground_color(color)).collect()
+        })
         .collect();
 
     let t: TableStruct = rows
@@ -49,17 +91,43 @@ pub fn print_table(header: Vec<String>, rows: Vec<Vec<String>>) {
         .title(h)
         .border(Border::builder().build())
         .separator(Separator::builder().build());
-    match print_stdout(t) {
-        Ok(r) => r,
-        Err(e) => panic!("{:?}", e),
-    };
+    print_stdout(t)
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Tag {
     pub key: String,
     pub value: Option<String>,
 }
 
+// a record whose fields can be looked up by name, e.g. for templates and filters.
+// tags are looked up with a `tag.` or `tag:` prefix.
+pub trait Record {
+    // look up a field other than tags. None when the field is unknown
+    fn attribute(&self, key: &str) -> Option<String>;
+    fn tags(&self) -> &[Tag];
+
+    fn field(&self, key: &str) -> Option<String> {
+        match tag_key(key) {
+            Some(k) => Some(tag_value(self.tags(), k)),
+            None => self.attribute(key),
+        }
+    }
+}
+
+pub fn tag_key(key: &str) -> Option<&str> {
+    key.strip_prefix("tag.")
+        .or_else(|| key.strip_prefix("tag:"))
+}
+
+// find a tag value. empty when the tag is missing
+pub fn tag_value(tags: &[Tag], key: &str) -> String {
+    tags.iter()
+        .find(|t| t.key == key)
+        .and_then(|t| t.value.clone())
+        .unwrap_or_default()
+}
+
 // find tag values
 pub fn get_values(tags: &[Tag], keys: &[String]) -> Vec<String> {
     let mut result: Vec<String> = vec!["".to_string(); keys.len()];
@@ -93,20 +161,17 @@ fn test_get_values() {
         },
     ];
     assert_eq!(
-        get_values(&tags, &vec!["Name".to_string()]),
+        get_values(&tags, &["Name".to_string()]),
         vec!["api".to_string()],
     );
     assert_eq!(
-        get_values(&tags, &vec!["Env".to_string()]),
+        get_values(&tags, &["Env".to_string()]),
         vec!["staging".to_string()],
     );
     assert_eq!(
-        get_values(&tags, &vec!["Env".to_string(), "Name".to_string()]),
+        get_values(&tags, &["Env".to_string(), "Name".to_string()]),
         vec!["staging".to_string(), "api".to_string()]
     );
-    assert_eq!(get_values(&tags, &vec!["env".to_string()]), vec![""]);
-    assert_eq!(
-        get_values(&tags, &vec!["ignore-monitor".to_string()]),
-        vec![""]
-    );
+    assert_eq!(get_values(&tags, &["env".to_string()]), vec![""]);
+    assert_eq!(get_values(&tags, &["ignore-monitor".to_string()]), vec![""]);
 }
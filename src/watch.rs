@@ -0,0 +1,51 @@
+use crate::error::{Error, Result};
+use crate::output::{OutputOpt, Watching};
+use std::future::Future;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct WatchOpt {
+    #[structopt(
+        long,
+        value_name = "seconds",
+        help = "Redraw the table every interval (2 seconds by default), highlighting changed rows"
+    )]
+    watch: Option<Option<u64>>,
+}
+
+impl WatchOpt {
+    pub fn is_set(&self) -> bool {
+        self.watch.is_some()
+    }
+
+    // the output redrawn in place while watching
+    pub fn output(&self, output: &OutputOpt) -> OutputOpt {
+        OutputOpt {
+            watch: Some(Watching::default()).filter(|_| self.is_set()),
+            ..output.clone()
+        }
+    }
+}
+
+// run once, or repeat every interval with --watch until interrupted
+pub async fn watch<F, Fut>(opt: &WatchOpt, mut run: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let interval = match opt.watch {
+        None => return run().await,
+        Some(i) => Duration::from_secs(i.unwrap_or(2).max(1)),
+    };
+    loop {
+        match run().await {
+            // the options never get valid
+            Err(e @ Error::Invalid(_)) => return Err(e),
+            // keep watching, e.g. until the instances come back or throttling ends
+            Err(e) => eprintln!("Error: {}", e),
+            Ok(()) => {}
+        }
+        tokio::time::sleep(interval).await;
+    }
+}